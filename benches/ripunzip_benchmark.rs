@@ -54,7 +54,7 @@ fn file_comparison(c: &mut Criterion, params: &ZipParams) {
         .join("ripunzip");
 
     let mut group = c.benchmark_group(&desc);
-    group.bench_function(&format!("{} ripunzip", &desc), |b| {
+    group.bench_function(format!("{} ripunzip", &desc), |b| {
         b.iter_batched(
             || create_output_dir_and_zip_file(params),
             |(output_dir, zip_file)| {
@@ -70,7 +70,7 @@ fn file_comparison(c: &mut Criterion, params: &ZipParams) {
             criterion::BatchSize::SmallInput,
         )
     });
-    group.bench_function(&format!("{} unzip", &desc), |b| {
+    group.bench_function(format!("{} unzip", &desc), |b| {
         b.iter_batched(
             || create_output_dir_and_zip_file(params),
             |(output_dir, zip_file)| {
@@ -103,7 +103,7 @@ fn uri_comparison(c: &mut Criterion, params: &ZipParams, server_type: ServerType
     let ripunzip_path = ripunzip_path();
 
     let mut group = c.benchmark_group(&desc);
-    group.bench_function(&format!("{} ripunzip", &desc), |b| {
+    group.bench_function(format!("{} ripunzip", &desc), |b| {
         b.iter_batched(
             create_output_dir_and_server,
             |(output_dir, server)| {
@@ -113,7 +113,7 @@ fn uri_comparison(c: &mut Criterion, params: &ZipParams, server_type: ServerType
             criterion::BatchSize::SmallInput,
         )
     });
-    group.bench_function(&format!("{} unzip", &desc), |b| {
+    group.bench_function(format!("{} unzip", &desc), |b| {
         b.iter_batched(
             create_output_dir_and_server,
             |(output_dir, server)| {
@@ -133,18 +133,18 @@ fn real_world_uri_comparison(c: &mut Criterion) {
     let ripunzip_path = ripunzip_path();
 
     let mut group = c.benchmark_group(desc);
-    group.bench_function(&format!("{} ripunzip", &desc), |b| {
+    group.bench_function(format!("{} ripunzip", &desc), |b| {
         b.iter_batched(
-            create_output_dir.clone(),
+            create_output_dir,
             |output_dir| {
                 fetch_uri_with_ripunzip(URI, output_dir, &ripunzip_path);
             },
             criterion::BatchSize::SmallInput,
         )
     });
-    group.bench_function(&format!("{} unzip", &desc), |b| {
+    group.bench_function(format!("{} unzip", &desc), |b| {
         b.iter_batched(
-            create_output_dir.clone(),
+            create_output_dir,
             |output_dir| fetch_uri_with_curl_and_unzip(URI, output_dir),
             criterion::BatchSize::SmallInput,
         )
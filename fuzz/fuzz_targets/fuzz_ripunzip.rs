@@ -84,6 +84,7 @@ fuzz_target!(|input: Inputs| {
     let options = ripunzip::UnzipOptions {
         single_threaded: input.single_threaded,
         output_directory: Some(output_directory.clone()),
+        ..Default::default()
     };
     let zipfile = tempdir.path().join("file.zip");
     let mut zip_data = Vec::new();
@@ -10,7 +10,30 @@
 
 mod unzip;
 
+#[cfg(feature = "async")]
+pub use unzip::AsyncUnzipEngine;
+pub use unzip::CancellationToken;
+pub use unzip::EntryFailure;
+pub use unzip::EntryFailures;
+pub use unzip::Error;
+pub use unzip::ExtractedFiles;
+pub use unzip::ExtractionLimits;
+pub use unzip::ExtractionSink;
+pub use unzip::FailureKind;
+pub use unzip::FailureMode;
+pub use unzip::FileMetadata;
+pub use unzip::FilenameFilter;
+pub use unzip::FilenamePattern;
+pub use unzip::FilesystemSink;
+pub use unzip::HeaderMismatch;
+pub use unzip::MemoryEntry;
+pub use unzip::MemorySink;
 pub use unzip::NullProgressReporter;
+pub use unzip::OverwritePolicy;
+pub use unzip::StreamingUnzipEngine;
+pub use unzip::SymlinkPolicy;
 pub use unzip::UnzipEngine;
 pub use unzip::UnzipOptions;
 pub use unzip::UnzipProgressReporter;
+pub use unzip::ValidationPolicy;
+pub use unzip::ZipEntryInfo;
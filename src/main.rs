@@ -8,12 +8,16 @@
 
 #![forbid(unsafe_code)]
 
-use std::{fmt::Write, fs::File, path::PathBuf};
+use std::{borrow::Cow, fmt::Write, fs::File, path::PathBuf};
 
-use anyhow::Result;
-use clap::{Parser, Subcommand};
-use indicatif::{ProgressBar, ProgressState, ProgressStyle};
-use ripunzip::{UnzipEngine, UnzipOptions, UnzipProgressReporter};
+use anyhow::{bail, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use indicatif::{MultiProgress, ProgressBar, ProgressState, ProgressStyle};
+use ripunzip::{
+    EntryFailure, FailureMode, FilenameFilter, FilenamePattern, NullProgressReporter,
+    OverwritePolicy, StreamingUnzipEngine, SymlinkPolicy, UnzipEngine, UnzipOptions,
+    UnzipProgressReporter, ValidationPolicy, ZipEntryInfo,
+};
 
 /// Unzip all files within a zip file as quickly as possible.
 #[derive(Parser, Debug)]
@@ -31,6 +35,118 @@ struct Args {
     /// multiple threads are used, but this can lead to more network traffic.
     #[arg(long)]
     single_threaded: bool,
+
+    /// What to do if a file being extracted already exists. By default,
+    /// existing files are overwritten.
+    #[arg(long, value_enum, value_name = "WHEN")]
+    overwrite: Option<OverwriteArg>,
+
+    /// Never overwrite existing files. Equivalent to `--overwrite never`.
+    #[arg(short = 'n', long, conflicts_with_all = ["overwrite", "update"])]
+    no_clobber: bool,
+
+    /// Only overwrite existing files if the file in the zip file is newer.
+    /// Equivalent to `--overwrite if-newer`. Times which the zip file only
+    /// records in MS-DOS format are taken to be UTC.
+    #[arg(short = 'u', long, conflicts_with = "overwrite")]
+    update: bool,
+
+    /// What to do with symlinks which might point outside the output
+    /// directory. Other symlinks are always created as symlinks.
+    #[arg(long, value_enum, value_name = "ACTION", default_value_t = UnsafeSymlinksArg::Error)]
+    unsafe_symlinks: UnsafeSymlinksArg,
+
+    /// Extract even if there doesn't seem to be enough free disk space.
+    #[arg(long)]
+    force: bool,
+
+    /// What to do if the data of entries within the zip file overlaps,
+    /// as it does in some zip bombs.
+    #[arg(long, value_enum, value_name = "ACTION", default_value_t = ValidationArg::Error)]
+    overlapping_entries: ValidationArg,
+
+    /// What to do if more than one entry would be extracted to the same
+    /// path. With error, only the first of them is extracted.
+    #[arg(long, value_enum, value_name = "ACTION", default_value_t = ValidationArg::Error)]
+    duplicate_names: ValidationArg,
+
+    /// Treat names which differ only in case as duplicates, as they would
+    /// be on case-insensitive filesystems.
+    #[arg(long)]
+    case_insensitive_names: bool,
+
+    /// Check that each file's local header agrees with the central
+    /// directory before extracting it.
+    #[arg(long)]
+    strict: bool,
+
+    /// Stop at the first file which fails, rather than trying all the
+    /// others and reporting every failure.
+    #[arg(long)]
+    fail_fast: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ValidationArg {
+    /// refuse to extract the zip file
+    Error,
+    /// warn, then extract anyway
+    Warn,
+    /// extract anyway
+    Allow,
+}
+
+impl From<ValidationArg> for ValidationPolicy {
+    fn from(arg: ValidationArg) -> Self {
+        match arg {
+            ValidationArg::Error => Self::Error,
+            ValidationArg::Warn => Self::Warn,
+            ValidationArg::Allow => Self::Allow,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum UnsafeSymlinksArg {
+    /// fail to extract them
+    Error,
+    /// skip them
+    Skip,
+    /// create regular files containing the symlink target instead
+    WriteAsFile,
+}
+
+impl From<UnsafeSymlinksArg> for SymlinkPolicy {
+    fn from(arg: UnsafeSymlinksArg) -> Self {
+        match arg {
+            UnsafeSymlinksArg::Error => Self::Error,
+            UnsafeSymlinksArg::Skip => Self::Skip,
+            UnsafeSymlinksArg::WriteAsFile => Self::WriteAsFile,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum OverwriteArg {
+    /// skip files which already exist
+    Never,
+    /// replace files which already exist
+    Always,
+    /// replace files which already exist only if they're older
+    IfNewer,
+    /// fail to extract files which already exist
+    Error,
+}
+
+impl From<OverwriteArg> for OverwritePolicy {
+    fn from(arg: OverwriteArg) -> Self {
+        match arg {
+            OverwriteArg::Never => Self::Never,
+            OverwriteArg::Always => Self::Always,
+            OverwriteArg::IfNewer => Self::IfNewer,
+            OverwriteArg::Error => Self::Error,
+        }
+    }
 }
 
 #[derive(Subcommand, Debug)]
@@ -40,6 +156,9 @@ enum Commands {
         /// Zip file to unzip
         #[arg(value_name = "FILE")]
         zipfile: PathBuf,
+
+        #[command(flatten)]
+        filter_args: FilterArgs,
     },
     /// downloads and unzips a zip file
     Uri {
@@ -53,9 +172,156 @@ enum Commands {
         /// problem, but may make transfers much less efficient by requiring multiple HTTP streams.
         #[arg(long, value_name = "BYTES")]
         readahead_limit: Option<usize>,
+
+        #[command(flatten)]
+        filter_args: FilterArgs,
+    },
+    /// unzips a zip file streamed to standard input, extracting each file
+    /// as soon as it arrives
+    Stdin {
+        #[command(flatten)]
+        filter_args: FilterArgs,
+    },
+    /// lists the contents of a zip file without extracting it
+    List {
+        #[command(subcommand)]
+        source: ListSource,
+
+        /// How to display the list of files. The machine-readable formats
+        /// also read each local file header to report where the file data
+        /// starts.
+        #[arg(long, value_enum, default_value_t = ListFormat::Table, global = true)]
+        format: ListFormat,
+    },
+    /// checks the integrity of a zip file by decompressing every file in
+    /// parallel and checking its CRC, without writing anything
+    Test {
+        #[command(subcommand)]
+        source: TestSource,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ListFormat {
+    /// a human-readable table
+    Table,
+    /// a JSON array with one object per file
+    Json,
+    /// CSV with a header row and one row per file
+    Csv,
+}
+
+#[derive(Subcommand, Debug)]
+enum ListSource {
+    /// lists the contents of a local zip file
+    File {
+        /// Zip file to list
+        #[arg(value_name = "FILE")]
+        zipfile: PathBuf,
+    },
+    /// lists the contents of a remote zip file. Where the server supports
+    /// HTTP ranges, only the central directory is downloaded.
+    Uri {
+        /// URI of zip file to list
+        #[arg(value_name = "URI")]
+        uri: String,
     },
 }
 
+#[derive(Subcommand, Debug)]
+enum TestSource {
+    /// tests a local zip file
+    File {
+        /// Zip file to test
+        #[arg(value_name = "FILE")]
+        zipfile: PathBuf,
+
+        #[command(flatten)]
+        filter_args: FilterArgs,
+    },
+    /// tests a remote zip file
+    Uri {
+        /// URI of zip file to test
+        #[arg(value_name = "URI")]
+        uri: String,
+
+        #[command(flatten)]
+        filter_args: FilterArgs,
+    },
+}
+
+/// Arguments to select a subset of files within the zip file.
+#[derive(clap::Args, Debug)]
+struct FilterArgs {
+    /// Only extract files whose names match this glob pattern. May be
+    /// specified multiple times, in which case files matching any of the
+    /// patterns are extracted.
+    #[arg(long, value_name = "GLOB")]
+    include: Vec<String>,
+
+    /// Don't extract files whose names match this glob pattern. May be
+    /// specified multiple times.
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// Only extract files whose names match this regular expression. May be
+    /// specified multiple times.
+    #[arg(long, value_name = "REGEX")]
+    include_regex: Vec<String>,
+
+    /// Don't extract files whose names match this regular expression. May be
+    /// specified multiple times.
+    #[arg(long, value_name = "REGEX")]
+    exclude_regex: Vec<String>,
+}
+
+impl Args {
+    /// The options for unzipping, given the filename filter of whichever
+    /// subcommand we're running.
+    fn unzip_options(&self, filter_args: &FilterArgs) -> Result<UnzipOptions> {
+        Ok(UnzipOptions {
+            output_directory: self.output_directory.clone(),
+            single_threaded: self.single_threaded,
+            filename_filter: filter_args.to_filter()?,
+            overwrite_policy: if self.no_clobber {
+                OverwritePolicy::Never
+            } else if self.update {
+                OverwritePolicy::IfNewer
+            } else {
+                self.overwrite.map(Into::into).unwrap_or_default()
+            },
+            symlink_policy: self.unsafe_symlinks.into(),
+            skip_free_space_check: self.force,
+            overlapping_entries: self.overlapping_entries.into(),
+            duplicate_names: self.duplicate_names.into(),
+            case_insensitive_names: self.case_insensitive_names,
+            strict: self.strict,
+            failure_mode: if self.fail_fast {
+                FailureMode::FailFast
+            } else {
+                FailureMode::KeepGoing
+            },
+            ..Default::default()
+        })
+    }
+}
+
+impl FilterArgs {
+    fn to_filter(&self) -> Result<FilenameFilter> {
+        let patterns = |globs: &[String], regexes: &[String]| {
+            globs
+                .iter()
+                .map(|glob| FilenamePattern::glob(glob))
+                .chain(regexes.iter().map(|regex| FilenamePattern::regex(regex)))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(FilenameFilter {
+            include: patterns(&self.include, &self.include_regex)?,
+            exclude: patterns(&self.exclude, &self.exclude_regex)?,
+        })
+    }
+}
+
 fn main() -> Result<()> {
     use std::io::Write;
 
@@ -74,55 +340,472 @@ fn main() -> Result<()> {
         })
         .init();
     let args = Args::parse();
-    let options = UnzipOptions {
-        output_directory: args.output_directory,
-        single_threaded: args.single_threaded,
-    };
-    match &args.command {
-        Commands::File { zipfile } => {
+    let result = match &args.command {
+        Commands::File {
+            zipfile,
+            filter_args,
+        } => {
             let zipfile = File::open(zipfile)?;
-            UnzipEngine::for_file(zipfile, options, ProgressDisplayer::new())?.unzip()
+            UnzipEngine::for_file(
+                zipfile,
+                args.unzip_options(filter_args)?,
+                ProgressDisplayer::new(),
+            )?
+            .unzip()
         }
         Commands::Uri {
             uri,
             readahead_limit,
+            filter_args,
         } => UnzipEngine::for_uri(
             uri,
-            options,
+            args.unzip_options(filter_args)?,
             *readahead_limit,
             ProgressDisplayer::new(),
             report_on_insufficient_readahead_size,
         )?
         .unzip(),
+        Commands::Stdin { filter_args } => {
+            let mut engine = StreamingUnzipEngine::new(
+                std::io::stdin().lock(),
+                args.unzip_options(filter_args)?,
+                ProgressDisplayer::new(),
+            );
+            if let Some(length) = stdin_length() {
+                engine = engine.with_length(length);
+            }
+            engine.unzip()
+        }
+        Commands::Test { source } => {
+            let (TestSource::File { filter_args, .. } | TestSource::Uri { filter_args, .. }) =
+                source;
+            return test(source, args.unzip_options(filter_args)?);
+        }
+        Commands::List { source, format } => return list(source, *format),
+    };
+    print_failures(result, "Files which failed to extract")
+}
+
+/// The error only describes the first file which failed, so list them all.
+fn print_failures(result: Result<(), ripunzip::Error>, summary: &str) -> Result<()> {
+    let failures = match result {
+        Ok(()) => return Ok(()),
+        Err(ripunzip::Error::Entries(failures)) => failures.failures,
+        Err(e) => return Err(e.into()),
+    };
+    write_failures(&failures, std::io::stderr().lock())?;
+    bail!("{summary}: {}", failures.len())
+}
+
+/// Write a line for each failure, giving the entry's name, its index in
+/// the central directory if known, and the kind of failure.
+fn write_failures(failures: &[EntryFailure], mut out: impl std::io::Write) -> Result<()> {
+    for failure in failures {
+        let index = failure
+            .index
+            .map_or_else(|| "-".to_string(), |index| index.to_string());
+        writeln!(
+            out,
+            "{} (index {index}, {:?}): {:#}",
+            failure.name, failure.kind, failure.error
+        )?;
     }
+    Ok(())
+}
+
+fn test(source: &TestSource, options: UnzipOptions) -> Result<()> {
+    let engine = match source {
+        TestSource::File { zipfile, .. } => {
+            let zipfile = File::open(zipfile)?;
+            UnzipEngine::for_file(zipfile, options, ProgressDisplayer::new())?
+        }
+        TestSource::Uri { uri, .. } => UnzipEngine::for_uri(
+            uri,
+            options,
+            None,
+            ProgressDisplayer::new(),
+            report_on_insufficient_readahead_size,
+        )?,
+    };
+    print_failures(engine.test(), "Corrupt files found")?;
+    println!("No errors detected");
+    Ok(())
+}
+
+fn list(source: &ListSource, format: ListFormat) -> Result<()> {
+    let mut engine = match source {
+        ListSource::File { zipfile } => {
+            let zipfile = File::open(zipfile)?;
+            UnzipEngine::for_file(zipfile, UnzipOptions::default(), NullProgressReporter)?
+        }
+        ListSource::Uri { uri } => UnzipEngine::for_uri(
+            uri,
+            UnzipOptions::default(),
+            None,
+            NullProgressReporter,
+            || {},
+        )?,
+    };
+    match format {
+        ListFormat::Table => print_table(&engine.entries()?),
+        ListFormat::Json => {
+            write_json(&engine.entries_with_data_start()?, std::io::stdout().lock())?
+        }
+        ListFormat::Csv => write_csv(&engine.entries_with_data_start()?, std::io::stdout().lock())?,
+    }
+    Ok(())
+}
+
+fn write_json(entries: &[ZipEntryInfo], mut out: impl std::io::Write) -> Result<()> {
+    let records: Vec<_> = entries.iter().map(ListRecord::from).collect();
+    serde_json::to_writer_pretty(&mut out, &records)?;
+    out.write_all(b"\n")?;
+    Ok(())
+}
+
+fn write_csv(entries: &[ZipEntryInfo], out: impl std::io::Write) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    for entry in entries {
+        writer.serialize(ListRecord::from(entry))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn print_table(entries: &[ZipEntryInfo]) {
+    println!(
+        "{:<10} {:>12} {:>12} {:<9} {:<8} {:<19} Name",
+        "Mode", "Size", "Compressed", "Method", "CRC-32", "Modified"
+    );
+    for entry in entries {
+        println!(
+            "{:<10} {:>12} {:>12} {:<9} {:08x} {} {}",
+            mode_string(entry),
+            entry.size,
+            entry.compressed_size,
+            entry.compression_method_name(),
+            entry.crc32,
+            format_date_time(&entry.last_modified, ' '),
+            entry.name
+        );
+    }
+}
+
+/// A single file in the machine-readable listing formats.
+#[derive(serde::Serialize)]
+struct ListRecord<'a> {
+    name: &'a str,
+    is_dir: bool,
+    size: u64,
+    compressed_size: u64,
+    compression_method: Cow<'static, str>,
+    crc32: u32,
+    last_modified: String,
+    unix_mode: Option<u32>,
+    encrypted: bool,
+    zip64: bool,
+    comment: &'a str,
+    header_start: u64,
+    data_start: Option<u64>,
+}
+
+impl<'a> From<&'a ZipEntryInfo> for ListRecord<'a> {
+    fn from(entry: &'a ZipEntryInfo) -> Self {
+        Self {
+            name: &entry.name,
+            is_dir: entry.is_dir,
+            size: entry.size,
+            compressed_size: entry.compressed_size,
+            compression_method: entry.compression_method_name(),
+            crc32: entry.crc32,
+            last_modified: format_date_time(&entry.last_modified, 'T'),
+            unix_mode: entry.unix_mode,
+            encrypted: entry.encrypted,
+            zip64: entry.zip64,
+            comment: &entry.comment,
+            header_start: entry.header_start,
+            data_start: entry.data_start,
+        }
+    }
+}
+
+fn format_date_time(date_time: &zip::DateTime, separator: char) -> String {
+    format!(
+        "{:04}-{:02}-{:02}{separator}{:02}:{:02}:{:02}",
+        date_time.year(),
+        date_time.month(),
+        date_time.day(),
+        date_time.hour(),
+        date_time.minute(),
+        date_time.second(),
+    )
+}
+
+/// Format the mode of an entry in the style of `ls -l`.
+fn mode_string(entry: &ZipEntryInfo) -> String {
+    let Some(mode) = entry.unix_mode else {
+        return if entry.is_dir {
+            "d?????????"
+        } else {
+            "-?????????"
+        }
+        .to_string();
+    };
+    let file_type = match mode & 0o170000 {
+        0o040000 => 'd',
+        0o120000 => 'l',
+        _ if entry.is_dir => 'd',
+        _ => '-',
+    };
+    std::iter::once(file_type)
+        .chain((0..9).map(|bit| {
+            if mode & (0o400 >> bit) == 0 {
+                '-'
+            } else {
+                ['r', 'w', 'x'][bit % 3]
+            }
+        }))
+        .collect()
+}
+
+/// The length of standard input, if it's redirected from a file.
+fn stdin_length() -> Option<u64> {
+    #[cfg(unix)]
+    let stdin = std::os::fd::AsFd::as_fd(&std::io::stdin()).try_clone_to_owned();
+    #[cfg(windows)]
+    let stdin = std::os::windows::io::AsHandle::as_handle(&std::io::stdin()).try_clone_to_owned();
+    #[cfg(not(any(unix, windows)))]
+    let stdin: std::io::Result<File> = Err(std::io::ErrorKind::Unsupported.into());
+    let metadata = File::from(stdin.ok()?).metadata().ok()?;
+    metadata.is_file().then_some(metadata.len())
 }
 
 fn report_on_insufficient_readahead_size() {
     eprintln!("Warning: this operation required several HTTP(S) streams.\nThis can slow down decompression.\nYou may wish to iuse --readahead-limit to increase the amount of data which can be held in memory.");
 }
 
-struct ProgressDisplayer(ProgressBar);
+/// Displays extraction progress in terms of compressed bytes, and where
+/// known, how much uncompressed data will be written. There's also a
+/// separate bar for downloading the zip file if it has to be fetched in its
+/// entirety.
+struct ProgressDisplayer {
+    bars: MultiProgress,
+    extraction: ProgressBar,
+    written: ProgressBar,
+    download: ProgressBar,
+}
 
 impl ProgressDisplayer {
     fn new() -> Self {
-        Self(ProgressBar::new(0))
+        let bars = MultiProgress::new();
+        // Until we know how much there is to extract, if ever, just count
+        // the bytes.
+        let extraction = bars.add(
+            ProgressBar::new(0).with_style(
+                ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] {bytes}\n{msg}")
+                    .unwrap(),
+            ),
+        );
+        Self {
+            bars,
+            extraction,
+            written: ProgressBar::hidden(),
+            download: ProgressBar::hidden(),
+        }
     }
 }
 
 impl UnzipProgressReporter for ProgressDisplayer {
     fn extraction_starting(&self, display_name: &str) {
-        self.0.set_message(format!("Extracting {display_name}"))
+        self.extraction
+            .set_message(format!("Extracting {display_name}"))
     }
 
     fn total_bytes_expected(&self, expected: u64) {
-        self.0.set_length(expected);
-        self.0.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})\n{msg}")
+        self.extraction.set_length(expected);
+        self.extraction.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})\n{msg}")
         .unwrap()
         .with_key("eta", |state: &ProgressState, w: &mut dyn Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
         .progress_chars("#-"));
     }
 
     fn bytes_extracted(&self, count: u64) {
-        self.0.inc(count)
+        self.extraction.inc(count)
+    }
+
+    fn total_uncompressed_bytes_expected(&self, expected: u64) {
+        let written = self
+            .bars
+            .insert_before(&self.extraction, self.written.clone());
+        written.set_length(expected);
+        written.set_style(
+            ProgressStyle::with_template(
+                "{spinner:.green} Writing [{wide_bar:.green/blue}] {bytes}/{total_bytes}",
+            )
+            .unwrap()
+            .progress_chars("#-"),
+        );
+    }
+
+    fn uncompressed_bytes_extracted(&self, count: u64) {
+        self.written.inc(count)
+    }
+
+    fn download_starting(&self, length: Option<u64>) {
+        let download = self.bars.insert(0, self.download.clone());
+        let template = match length {
+            Some(length) => {
+                download.set_length(length);
+                "{spinner:.green} Downloading [{wide_bar:.yellow/blue}] {bytes}/{total_bytes} ({bytes_per_sec})"
+            }
+            None => "{spinner:.green} Downloading {bytes} ({bytes_per_sec})",
+        };
+        download.set_style(
+            ProgressStyle::with_template(template)
+                .unwrap()
+                .progress_chars("#-"),
+        );
+    }
+
+    fn bytes_downloaded(&self, count: u64) {
+        self.download.inc(count)
+    }
+
+    fn download_finished(&self) {
+        self.download.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, io::Write};
+    use tempfile::tempdir;
+    use test_log::test;
+    use zip::{write::FileOptions, ZipWriter};
+
+    use ripunzip::{Error, NullProgressReporter, UnzipEngine, UnzipOptions, ZipEntryInfo};
+
+    use super::{write_csv, write_failures, write_json};
+
+    fn entries() -> Vec<ZipEntryInfo> {
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        let mut zip = ZipWriter::new(File::create(&zf).unwrap());
+        zip.add_directory("test/", Default::default()).unwrap();
+        let options = FileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored)
+            .unix_permissions(0o644)
+            .last_modified_time(zip::DateTime::from_date_and_time(2023, 4, 5, 6, 7, 8).unwrap());
+        zip.start_file("test/a.txt", options).unwrap();
+        zip.write_all(b"Contents of A\n").unwrap();
+        zip.finish().unwrap();
+        UnzipEngine::for_file(
+            File::open(&zf).unwrap(),
+            UnzipOptions::default(),
+            NullProgressReporter,
+        )
+        .unwrap()
+        .entries_with_data_start()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_list_json() {
+        let mut out = Vec::new();
+        write_json(&entries(), &mut out).unwrap();
+        let records: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        let records = records.as_array().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["name"], "test/");
+        assert_eq!(records[0]["is_dir"], true);
+        let file = &records[1];
+        assert_eq!(file["name"], "test/a.txt");
+        assert_eq!(file["is_dir"], false);
+        assert_eq!(file["size"], 14);
+        assert_eq!(file["compressed_size"], 14);
+        assert_eq!(file["compression_method"], "stored");
+        assert_eq!(file["crc32"], crc32fast::hash(b"Contents of A\n"));
+        assert_eq!(file["last_modified"], "2023-04-05T06:07:08");
+        assert_eq!(file["unix_mode"], 0o100644);
+        assert_eq!(file["encrypted"], false);
+        assert_eq!(file["zip64"], false);
+        assert_eq!(file["comment"], "");
+        let header_start = file["header_start"].as_u64().unwrap();
+        // A local header is 30 bytes, followed by the name and extra field.
+        assert!(file["data_start"].as_u64().unwrap() >= header_start + 30 + 10);
+    }
+
+    #[test]
+    fn test_list_csv() {
+        let mut out = Vec::new();
+        write_csv(&entries(), &mut out).unwrap();
+        let mut reader = csv::Reader::from_reader(&out[..]);
+        assert_eq!(
+            reader.headers().unwrap(),
+            vec![
+                "name",
+                "is_dir",
+                "size",
+                "compressed_size",
+                "compression_method",
+                "crc32",
+                "last_modified",
+                "unix_mode",
+                "encrypted",
+                "zip64",
+                "comment",
+                "header_start",
+                "data_start",
+            ]
+        );
+        let rows: Vec<_> = reader.records().map(Result::unwrap).collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(&rows[0][0], "test/");
+        assert_eq!(&rows[0][1], "true");
+        let file = &rows[1];
+        assert_eq!(&file[0], "test/a.txt");
+        assert_eq!(&file[1], "false");
+        assert_eq!(&file[2], "14");
+        assert_eq!(&file[4], "stored");
+        assert_eq!(&file[6], "2023-04-05T06:07:08");
+        assert_eq!(&file[7], (0o100644).to_string());
+        assert!(file[12].parse::<u64>().unwrap() > file[11].parse::<u64>().unwrap());
+    }
+
+    #[test]
+    fn test_failures() {
+        let mut zip = ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        let options = FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        zip.start_file("a.txt", options).unwrap();
+        zip.write_all(b"Contents of A\n").unwrap();
+        zip.start_file("b.txt", options).unwrap();
+        zip.write_all(b"Contents of B\n").unwrap();
+        let mut zip_data = zip.finish().unwrap().into_inner();
+        // Corrupt the contents of b.txt.
+        let pos = zip_data
+            .windows(13)
+            .position(|window| window == b"Contents of B")
+            .unwrap();
+        zip_data[pos] = b'X';
+        let Err(Error::Entries(failures)) = UnzipEngine::for_reader(
+            std::io::Cursor::new(zip_data),
+            UnzipOptions::default(),
+            NullProgressReporter,
+        )
+        .unwrap()
+        .test() else {
+            panic!("Corruption wasn't detected");
+        };
+        let mut out = Vec::new();
+        write_failures(&failures.failures, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), 1);
+        assert!(
+            lines[0].starts_with("b.txt (index 1, Archive): "),
+            "{}",
+            lines[0]
+        );
+        assert!(lines[0].contains("Invalid checksum"), "{}", lines[0]);
     }
 }
@@ -0,0 +1,683 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! An async front-end to [`UnzipEngine`], for use within a tokio runtime.
+//! HTTP requests are made asynchronously, using the caller's
+//! [`reqwest::Client`], while decompression still happens on rayon threads.
+//! Those threads ask for data over channels, and block until it arrives,
+//! so we only fetch as fast as we can extract.
+
+use std::{
+    cmp::min,
+    collections::VecDeque,
+    future::Future,
+    io::{Read, Seek, SeekFrom},
+    ops::Range,
+    panic::{catch_unwind, AssertUnwindSafe},
+    pin::pin,
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use futures_util::{
+    future::{select, Either},
+    stream::{FuturesUnordered, StreamExt},
+};
+use reqwest::{header::ACCEPT_RANGES, Client, Response, StatusCode};
+use tokio::sync::{mpsc, oneshot};
+
+use super::{
+    error::ClassifiedError, http_range_reader::content_length_via_headers, CancellationToken,
+    Error, ExtractionSink, FailureKind, UnzipEngine, UnzipOptions, UnzipProgressReporter,
+};
+
+/// How much of the zip file to fetch in each range request. Requests are
+/// aligned to multiples of this size, so that readers of nearby entries
+/// can share the same chunk.
+const FETCH_SIZE: u64 = 1024 * 1024;
+
+/// How many recently fetched chunks to keep for other readers to share.
+const CACHED_CHUNKS: usize = 16;
+
+/// How many chunks of a download which doesn't support ranges to buffer,
+/// ahead of extraction.
+const DOWNLOAD_QUEUE: usize = 16;
+
+/// An object which can unzip a URI from within an async runtime. Like
+/// [`UnzipEngine::for_uri`], it fetches the zip file in chunks, as
+/// extraction reaches them, from servers which support HTTP ranges, and
+/// otherwise extracts files as the zip file downloads.
+///
+/// Dropping the future returned by [`AsyncUnzipEngine::unzip`],
+/// [`AsyncUnzipEngine::unzip_into`] or [`AsyncUnzipEngine::test`] cancels the operation, as if
+/// [`UnzipOptions::cancellation_token`] had been cancelled.
+pub struct AsyncUnzipEngine<P: UnzipProgressReporter + Send + 'static> {
+    client: Client,
+    uri: String,
+    options: UnzipOptions,
+    progress_reporter: P,
+    source: AsyncSource,
+}
+
+/// How we'll fetch the zip file.
+enum AsyncSource {
+    /// Using range requests for a resource of this length.
+    Ranges(u64),
+    /// From start to finish, using this response.
+    Download(Response),
+}
+
+impl<P: UnzipProgressReporter + Send + 'static> AsyncUnzipEngine<P> {
+    /// Create an unzip engine which knows how to unzip a URI, using the
+    /// given HTTP client.
+    pub async fn for_uri(
+        uri: &str,
+        client: Client,
+        options: UnzipOptions,
+        progress_reporter: P,
+    ) -> Result<Self, Error> {
+        let head = client
+            .head(uri)
+            .send()
+            .await
+            .and_then(Response::error_for_status);
+        let length = head
+            .ok()
+            .filter(|response| response.headers().contains_key(ACCEPT_RANGES))
+            .and_then(|response| content_length_via_headers(response.headers()))
+            .filter(|length| *length > 0);
+        let source = match length {
+            Some(length) => AsyncSource::Ranges(length),
+            None => {
+                // This server probably doesn't support HTTP ranges.
+                let response = client.get(uri).send().await?.error_for_status()?;
+                AsyncSource::Download(response)
+            }
+        };
+        Ok(Self {
+            client,
+            uri: uri.to_string(),
+            options,
+            progress_reporter,
+            source,
+        })
+    }
+
+    /// The total compressed length that we expect to retrieve over
+    /// the network.
+    pub fn zip_length(&self) -> u64 {
+        match &self.source {
+            AsyncSource::Ranges(length) => *length,
+            AsyncSource::Download(response) => response.content_length().unwrap_or_default(),
+        }
+    }
+
+    /// Perform the unzip, as [`UnzipEngine::unzip`].
+    pub async fn unzip(self) -> Result<(), Error> {
+        self.run(UnzipEngine::unzip).await
+    }
+
+    /// Perform the unzip, as [`UnzipEngine::unzip_into`], into `sink`
+    /// rather than the output directory. The sink is shared with the
+    /// extraction threads, so keep a clone of it to see what was extracted.
+    pub async fn unzip_into(self, sink: Arc<dyn ExtractionSink + Send>) -> Result<(), Error> {
+        self.run(move |engine| engine.unzip_into(&*sink)).await
+    }
+
+    /// Check the integrity of the files we'd extract, as
+    /// [`UnzipEngine::test`].
+    pub async fn test(self) -> Result<(), Error> {
+        self.run(UnzipEngine::test).await
+    }
+
+    /// Run `operation` on a rayon thread, while fetching whatever data it
+    /// asks for.
+    async fn run(
+        self,
+        operation: impl FnOnce(UnzipEngine<P>) -> Result<(), Error> + Send + 'static,
+    ) -> Result<(), Error> {
+        let Self {
+            client,
+            uri,
+            options,
+            progress_reporter,
+            source,
+        } = self;
+        let token = options.cancellation_token.clone();
+        match source {
+            AsyncSource::Ranges(length) => {
+                let (requests, receiver) = mpsc::channel(1);
+                let reader = RangeReader::new(requests, length);
+                let job = move || {
+                    operation(UnzipEngine::for_cloneable_reader(
+                        reader,
+                        length,
+                        options,
+                        progress_reporter,
+                    )?)
+                };
+                drive(job, serve_ranges(client, uri, receiver), token).await
+            }
+            AsyncSource::Download(response) => {
+                let length = response.content_length();
+                let (chunks, receiver) = mpsc::channel(DOWNLOAD_QUEUE);
+                let job = move || {
+                    operation(UnzipEngine::for_download(
+                        Box::new(DownloadReader::new(receiver)),
+                        length,
+                        options,
+                        progress_reporter,
+                    ))
+                };
+                drive(job, download(response, chunks), token).await
+            }
+        }
+    }
+}
+
+/// Run `job` on a rayon thread, polling `io` until it's finished. If we're
+/// dropped first, the job is cancelled. A panic in the job is returned as
+/// an error, rather than unwinding into rayon, which would abort the
+/// process.
+async fn drive(
+    job: impl FnOnce() -> Result<(), Error> + Send + 'static,
+    io: impl Future<Output = ()>,
+    token: CancellationToken,
+) -> Result<(), Error> {
+    let (result_sender, result) = oneshot::channel();
+    rayon::spawn(move || {
+        let result = catch_unwind(AssertUnwindSafe(job))
+            .unwrap_or_else(|_| Err(Error::Other("Extraction thread panicked".into())));
+        let _ = result_sender.send(result);
+    });
+    let guard = CancelOnDrop(Some(token));
+    let io = pin!(io);
+    let result = match select(result, io).await {
+        Either::Left((result, _)) => result,
+        // The download finished, but extraction hasn't yet.
+        Either::Right(((), result)) => result.await,
+    };
+    guard.disarm();
+    result.unwrap_or_else(|_| Err(Error::Other("Extraction thread panicked".into())))
+}
+
+/// Cancels a [`CancellationToken`] when dropped, unless disarmed.
+struct CancelOnDrop(Option<CancellationToken>);
+
+impl CancelOnDrop {
+    fn disarm(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if let Some(token) = &self.0 {
+            token.cancel();
+        }
+    }
+}
+
+/// A request from a [`RangeReader`] for part of the zip file.
+struct RangeRequest {
+    range: Range<u64>,
+    reply: oneshot::Sender<std::io::Result<Bytes>>,
+}
+
+/// Fetch ranges as [`RangeReader`]s ask for them, until they've all gone.
+/// Requests from different readers are fetched concurrently.
+async fn serve_ranges(client: Client, uri: String, mut requests: mpsc::Receiver<RangeRequest>) {
+    let mut fetches = FuturesUnordered::new();
+    loop {
+        let request = if fetches.is_empty() {
+            requests.recv().await
+        } else {
+            match select(pin!(requests.recv()), fetches.next()).await {
+                Either::Left((request, _)) => request,
+                Either::Right(_) => continue,
+            }
+        };
+        let Some(RangeRequest { range, reply }) = request else {
+            break;
+        };
+        let (client, uri) = (client.clone(), uri.clone());
+        fetches.push(async move {
+            let _ = reply.send(fetch_range(&client, &uri, range).await);
+        });
+    }
+}
+
+async fn fetch_range(client: &Client, uri: &str, range: Range<u64>) -> std::io::Result<Bytes> {
+    log::info!("Fetch range 0x{:x}-0x{:x}", range.start, range.end);
+    // HTTP ranges are inclusive of the last byte.
+    let range_header = format!("bytes={}-{}", range.start, range.end - 1);
+    let response = client
+        .get(uri)
+        .header(reqwest::header::RANGE, range_header)
+        .send()
+        .await
+        .and_then(Response::error_for_status)
+        .map_err(std::io::Error::other)?;
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+        return Err(transport_error(format!(
+            "Server responded to a range request with {}",
+            response.status()
+        )));
+    }
+    let data = response.bytes().await.map_err(std::io::Error::other)?;
+    if data.len() as u64 != range.end - range.start {
+        return Err(transport_error(format!(
+            "Server returned {} bytes for a range of {}",
+            data.len(),
+            range.end - range.start
+        )));
+    }
+    Ok(data)
+}
+
+fn transport_error(message: impl Into<String>) -> std::io::Error {
+    std::io::Error::other(ClassifiedError::new(FailureKind::Transport, message))
+}
+
+/// A chunk of the zip file, which is `None` until it's been fetched. Its
+/// lock is held while fetching, so that other readers wait for the fetch
+/// rather than repeating it.
+type Chunk = Arc<Mutex<Option<Bytes>>>;
+
+/// A [`Read`] and [`Seek`] over a zip file on an HTTP server, which asks
+/// [`serve_ranges`] for each chunk it needs and blocks until it arrives.
+/// Clones have their own position and buffer, so can be read in parallel,
+/// but share recently fetched chunks, so that each clone doesn't fetch the
+/// chunk containing its entry all over again.
+#[derive(Clone)]
+struct RangeReader {
+    requests: mpsc::Sender<RangeRequest>,
+    length: u64,
+    pos: u64,
+    buffer: Bytes,
+    buffer_start: u64,
+    /// Recently used chunks, by start position, least recent first.
+    chunks: Arc<Mutex<VecDeque<(u64, Chunk)>>>,
+}
+
+impl RangeReader {
+    fn new(requests: mpsc::Sender<RangeRequest>, length: u64) -> Self {
+        Self {
+            requests,
+            length,
+            pos: 0,
+            buffer: Bytes::new(),
+            buffer_start: 0,
+            chunks: Arc::default(),
+        }
+    }
+
+    /// Get the chunk starting at `start`, from the cache if another reader
+    /// has already fetched it.
+    fn chunk(&self, start: u64) -> std::io::Result<Bytes> {
+        let chunk = {
+            let mut chunks = self.chunks.lock().unwrap();
+            let chunk = match chunks.iter().position(|(pos, _)| *pos == start) {
+                Some(index) => chunks.remove(index).unwrap().1,
+                None => Chunk::default(),
+            };
+            chunks.push_back((start, chunk.clone()));
+            if chunks.len() > CACHED_CHUNKS {
+                chunks.pop_front();
+            }
+            chunk
+        };
+        let mut data = chunk.lock().unwrap();
+        if let Some(data) = &*data {
+            return Ok(data.clone());
+        }
+        let fetched = self.fetch(start..min(start + FETCH_SIZE, self.length))?;
+        *data = Some(fetched.clone());
+        Ok(fetched)
+    }
+
+    fn fetch(&self, range: Range<u64>) -> std::io::Result<Bytes> {
+        let aborted = || transport_error("Download aborted");
+        let (reply, response) = oneshot::channel();
+        self.requests
+            .blocking_send(RangeRequest { range, reply })
+            .map_err(|_| aborted())?;
+        response.blocking_recv().map_err(|_| aborted())?
+    }
+}
+
+impl Read for RangeReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.length || buf.is_empty() {
+            return Ok(0);
+        }
+        let offset = match self.pos.checked_sub(self.buffer_start) {
+            Some(offset) if offset < self.buffer.len() as u64 => offset as usize,
+            _ => {
+                let start = self.pos - self.pos % FETCH_SIZE;
+                self.buffer = self.chunk(start)?;
+                self.buffer_start = start;
+                (self.pos - start) as usize
+            }
+        };
+        let available = &self.buffer[offset..];
+        let bytes_read = min(available.len(), buf.len());
+        buf[..bytes_read].copy_from_slice(&available[..bytes_read]);
+        self.pos += bytes_read as u64;
+        Ok(bytes_read)
+    }
+}
+
+impl Seek for RangeReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.length.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = new_pos.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Attempted to seek before beginning of file",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+/// Pass the body of `response` to a [`DownloadReader`], a chunk at a time.
+async fn download(mut response: Response, chunks: mpsc::Sender<std::io::Result<Bytes>>) {
+    loop {
+        let chunk = match response.chunk().await {
+            Ok(Some(chunk)) => Ok(chunk),
+            Ok(None) => break,
+            Err(e) => Err(std::io::Error::other(e)),
+        };
+        let failed = chunk.is_err();
+        if chunks.send(chunk).await.is_err() || failed {
+            break;
+        }
+    }
+}
+
+/// A [`Read`] over a download, which blocks until [`download`] passes it
+/// each chunk.
+struct DownloadReader {
+    chunks: mpsc::Receiver<std::io::Result<Bytes>>,
+    chunk: Bytes,
+}
+
+impl DownloadReader {
+    fn new(chunks: mpsc::Receiver<std::io::Result<Bytes>>) -> Self {
+        Self {
+            chunks,
+            chunk: Bytes::new(),
+        }
+    }
+}
+
+impl Read for DownloadReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.chunks.blocking_recv() {
+                Some(chunk) => self.chunk = chunk?,
+                None => return Ok(0),
+            }
+        }
+        let bytes_read = min(self.chunk.len(), buf.len());
+        buf[..bytes_read].copy_from_slice(&self.chunk.split_to(bytes_read));
+        Ok(bytes_read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Cursor, Write},
+        path::Path,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use httptest::{matchers::request::method_path, responders::*, Expectation, Server};
+    use ripunzip_test_utils::*;
+    use tempfile::tempdir;
+    use test_log::test;
+    use tokio::sync::mpsc;
+    use zip::{write::FileOptions, ZipWriter};
+
+    use super::{download, drive, AsyncUnzipEngine, RangeReader, RangeRequest, DOWNLOAD_QUEUE};
+    use crate::{
+        CancellationToken, Error, MemoryEntry, MemorySink, NullProgressReporter, UnzipEngine,
+        UnzipOptions, UnzipProgressReporter,
+    };
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    fn extract_from_server(server_type: ServerType) {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.add_directory("test/", FileOptions::default()).unwrap();
+        zip.start_file("test/a.txt", FileOptions::default())
+            .unwrap();
+        zip.write_all(b"Contents of A\n").unwrap();
+        // Big enough to need more than one range request.
+        zip.start_file(
+            "big.bin",
+            FileOptions::default().compression_method(zip::CompressionMethod::Stored),
+        )
+        .unwrap();
+        let big: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+        zip.write_all(&big).unwrap();
+        let body = zip.finish().unwrap().into_inner();
+        let server = Server::run();
+        set_up_server(&server, body, server_type);
+
+        let td = tempdir().unwrap();
+        let outdir = td.path().join("outdir");
+        runtime().block_on(async {
+            let options = UnzipOptions {
+                output_directory: Some(outdir.clone()),
+                ..Default::default()
+            };
+            AsyncUnzipEngine::for_uri(
+                &server.url("/foo").to_string(),
+                reqwest::Client::new(),
+                options,
+                NullProgressReporter,
+            )
+            .await
+            .unwrap()
+            .unzip()
+            .await
+            .unwrap();
+        });
+        assert_eq!(
+            std::fs::read_to_string(outdir.join("test/a.txt")).unwrap(),
+            "Contents of A\n"
+        );
+        assert_eq!(std::fs::read(outdir.join("big.bin")).unwrap(), big);
+    }
+
+    #[test]
+    fn test_extract_from_server() {
+        extract_from_server(ServerType::Ranges)
+    }
+
+    #[test]
+    fn test_extract_from_no_range_server() {
+        extract_from_server(ServerType::ContentLengthButNoRanges)
+    }
+
+    #[test]
+    fn test_unzip_into_sink() {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("a.txt", FileOptions::default()).unwrap();
+        zip.write_all(b"Contents of A\n").unwrap();
+        let body = zip.finish().unwrap().into_inner();
+        for server_type in [ServerType::Ranges, ServerType::ContentLengthButNoRanges] {
+            let server = Server::run();
+            set_up_server(&server, body.clone(), server_type);
+            let sink = Arc::new(MemorySink::new());
+            runtime().block_on(async {
+                AsyncUnzipEngine::for_uri(
+                    &server.url("/foo").to_string(),
+                    reqwest::Client::new(),
+                    UnzipOptions::default(),
+                    NullProgressReporter,
+                )
+                .await
+                .unwrap()
+                .unzip_into(sink.clone())
+                .await
+                .unwrap();
+            });
+            let entries = Arc::try_unwrap(sink).unwrap().into_entries();
+            assert!(matches!(
+                &entries[Path::new("a.txt")],
+                MemoryEntry::File { data, .. } if data == b"Contents of A\n"
+            ));
+        }
+    }
+
+    #[test]
+    fn test_panicking_job_is_an_error() {
+        let result = runtime().block_on(drive(
+            || panic!("Corrupt archive"),
+            std::future::pending(),
+            CancellationToken::new(),
+        ));
+        assert!(matches!(result, Err(Error::Other(_))));
+    }
+
+    #[test]
+    fn test_readers_share_chunks() {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        for i in 0..20000 {
+            zip.start_file(format!("{i}.txt"), options).unwrap();
+            zip.write_all(b"Small file\n").unwrap();
+        }
+        let body = zip.finish().unwrap().into_inner();
+        let length = body.len() as u64;
+
+        // Serve ranges from memory, counting how much we're asked for.
+        let (requests, mut receiver) = mpsc::channel::<RangeRequest>(1);
+        let fetched = Arc::new(AtomicU64::new(0));
+        let server_fetched = fetched.clone();
+        std::thread::spawn(move || {
+            while let Some(RangeRequest { range, reply }) = receiver.blocking_recv() {
+                server_fetched.fetch_add(range.end - range.start, Ordering::SeqCst);
+                let data = body[range.start as usize..range.end as usize].to_vec();
+                let _ = reply.send(Ok(data.into()));
+            }
+        });
+        UnzipEngine::for_cloneable_reader(
+            RangeReader::new(requests, length),
+            length,
+            UnzipOptions::default(),
+            NullProgressReporter,
+        )
+        .unwrap()
+        .test()
+        .unwrap();
+        // Each of the 20000 clones would otherwise fetch its own chunk.
+        assert!(fetched.load(Ordering::SeqCst) <= 2 * length);
+    }
+
+    /// Tells the test when the extraction job has finished with it.
+    struct DropSignal(std::sync::mpsc::Sender<()>);
+
+    impl UnzipProgressReporter for DropSignal {}
+
+    impl Drop for DropSignal {
+        fn drop(&mut self) {
+            let _ = self.0.send(());
+        }
+    }
+
+    #[test]
+    fn test_dropping_future_cancels() {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("a.txt", FileOptions::default()).unwrap();
+        zip.write_all(b"Contents of A\n").unwrap();
+        let body = zip.finish().unwrap().into_inner();
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(method_path("HEAD", "/foo")).respond_with(
+                RangeAwareResponse::new(200, RangeAwareResponseType::LengthOnly(body.len())),
+            ),
+        );
+        // Never answer in time.
+        server.expect(
+            Expectation::matching(method_path("GET", "/foo"))
+                .times(..)
+                .respond_with(delay_and_then(
+                    Duration::from_secs(10),
+                    RangeAwareResponse::new(206, RangeAwareResponseType::Body(body.into())),
+                )),
+        );
+
+        let token = CancellationToken::new();
+        let (finished, job_finished) = std::sync::mpsc::channel();
+        runtime().block_on(async {
+            let options = UnzipOptions {
+                output_directory: Some(tempdir().unwrap().into_path()),
+                cancellation_token: token.clone(),
+                ..Default::default()
+            };
+            let engine = AsyncUnzipEngine::for_uri(
+                &server.url("/foo").to_string(),
+                reqwest::Client::new(),
+                options,
+                DropSignal(finished),
+            )
+            .await
+            .unwrap();
+            let unzip = engine.unzip();
+            assert!(tokio::time::timeout(Duration::from_millis(200), unzip)
+                .await
+                .is_err());
+        });
+        assert!(token.is_cancelled());
+        // The extraction thread was blocked waiting for the response, and
+        // should give up without waiting for it.
+        job_finished.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_download_backpressure() {
+        let body = vec![0u8; 32 * 1024 * 1024];
+        let server = Server::run();
+        set_up_server(&server, body, ServerType::ContentLengthButNoRanges);
+        let runtime = runtime();
+        let (chunks, mut receiver) = mpsc::channel(DOWNLOAD_QUEUE);
+        runtime.block_on(async {
+            let response = reqwest::get(server.url("/foo").to_string()).await.unwrap();
+            // Nobody is reading, so the download should stall once the
+            // queue is full.
+            assert!(
+                tokio::time::timeout(Duration::from_millis(500), download(response, chunks))
+                    .await
+                    .is_err()
+            );
+        });
+        let mut queued = 0;
+        while receiver.try_recv().is_ok() {
+            queued += 1;
+        }
+        assert_eq!(queued, DOWNLOAD_QUEUE);
+    }
+}
@@ -0,0 +1,190 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    io::Read,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use super::{error::ClassifiedError, FailureKind};
+
+/// A callback to run when a [`CancellationToken`] is cancelled.
+type OnCancel = Box<dyn FnOnce() + Send>;
+
+/// Cancels an extraction, typically from another thread. Clones of a token
+/// share the same state, so keep a clone before passing one in
+/// [`super::UnzipOptions`].
+///
+/// Once cancelled, no more files are started, files which are part-way
+/// through extraction are abandoned and removed, and any download stops.
+/// The extraction then fails with [`super::Error::Cancelled`].
+#[derive(Clone, Default)]
+pub struct CancellationToken(Arc<Inner>);
+
+#[derive(Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    /// Callbacks to run, keyed by their registration IDs.
+    on_cancel: Mutex<Vec<(u64, OnCancel)>>,
+    next_id: AtomicU64,
+}
+
+impl CancellationToken {
+    /// Create a token which hasn't been cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel any extraction using this token.
+    pub fn cancel(&self) {
+        if self.0.cancelled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let callbacks = std::mem::take(&mut *self.0.on_cancel.lock().unwrap());
+        for (_, callback) in callbacks {
+            callback();
+        }
+    }
+
+    /// Whether [`Self::cancel`] has been called.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Run `callback` once we're cancelled, or straight away if we already
+    /// have been. This is for waking things which don't poll the token.
+    /// The callback is forgotten once the returned registration is dropped.
+    pub(crate) fn on_cancel(&self, callback: impl FnOnce() + Send + 'static) -> CancelRegistration {
+        let id = self.0.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut callbacks = self.0.on_cancel.lock().unwrap();
+        if self.is_cancelled() {
+            drop(callbacks);
+            callback();
+        } else {
+            callbacks.push((id, Box::new(callback)));
+        }
+        CancelRegistration {
+            token: self.clone(),
+            id,
+        }
+    }
+
+    /// Fail if we've been cancelled.
+    pub(crate) fn check(&self) -> std::io::Result<()> {
+        if self.is_cancelled() {
+            Err(std::io::Error::other(ClassifiedError::new(
+                FailureKind::Cancelled,
+                "Extraction was cancelled",
+            )))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// A callback registered with [`CancellationToken::on_cancel`], which is
+/// removed when this is dropped, so that a token which is reused for many
+/// extractions doesn't accumulate callbacks for ones which have finished.
+#[must_use]
+pub(crate) struct CancelRegistration {
+    token: CancellationToken,
+    id: u64,
+}
+
+impl Drop for CancelRegistration {
+    fn drop(&mut self) {
+        self.token
+            .0
+            .on_cancel
+            .lock()
+            .unwrap()
+            .retain(|(id, _)| *id != self.id);
+    }
+}
+
+/// A [`Read`] which fails once its [`CancellationToken`] is cancelled, to
+/// interrupt copies which are in progress.
+pub(crate) struct CancellableReader<R: Read> {
+    inner: R,
+    token: CancellationToken,
+}
+
+impl<R: Read> CancellableReader<R> {
+    pub(crate) fn new(inner: R, token: CancellationToken) -> Self {
+        Self { inner, token }
+    }
+}
+
+impl<R: Read> Read for CancellableReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.token.check()?;
+        self.inner.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Read,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+    use test_log::test;
+
+    use super::{CancellableReader, CancellationToken};
+
+    #[test]
+    fn test_cancellation_token() {
+        let token = CancellationToken::new();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let _registration = token.on_cancel(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let mut reader = CancellableReader::new(&b"abcdef"[..], token.clone());
+        let mut buf = [0u8; 3];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        token.clone().cancel();
+        token.cancel();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(reader.read_exact(&mut buf).is_err());
+        // Callbacks registered after cancellation run immediately.
+        let counter = calls.clone();
+        let _registration = token.on_cancel(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_cancel_registration() {
+        let token = CancellationToken::new();
+        let calls = Arc::new(AtomicUsize::new(0));
+        for _ in 0..3 {
+            let counter = calls.clone();
+            drop(token.on_cancel(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }));
+        }
+        assert!(token.0.on_cancel.lock().unwrap().is_empty());
+        let counter = calls.clone();
+        let registration = token.on_cancel(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        assert_eq!(token.0.on_cancel.lock().unwrap().len(), 1);
+        token.cancel();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        drop(registration);
+    }
+}
@@ -0,0 +1,495 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A minimal parser for the central directory of a zip file. `zip-rs` parses
+//! this too, but only exposes the details of each entry once its local
+//! header has been read, which for a remote zip file involves a network
+//! round-trip per entry. This parser gives us everything we need to plan
+//! an extraction without touching anything except the end of the file.
+
+use std::{
+    borrow::Cow,
+    io::{Read, Seek, SeekFrom},
+    path::{Component, Path},
+};
+
+use anyhow::{Context, Result};
+
+use super::error::fail;
+
+pub(crate) const CENTRAL_DIRECTORY_END_SIGNATURE: u32 = 0x06054b50;
+const ZIP64_CENTRAL_DIRECTORY_END_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+pub(crate) const ZIP64_CENTRAL_DIRECTORY_END_SIGNATURE: u32 = 0x06064b50;
+pub(crate) const CENTRAL_DIRECTORY_HEADER_SIGNATURE: u32 = 0x02014b50;
+
+const CENTRAL_DIRECTORY_END_SIZE: u64 = 22;
+const ZIP64_CENTRAL_DIRECTORY_END_LOCATOR_SIZE: u64 = 20;
+const ZIP64_CENTRAL_DIRECTORY_END_SIZE: usize = 56;
+const CENTRAL_DIRECTORY_HEADER_SIZE: usize = 46;
+/// The fixed-size part of a local file header.
+pub(crate) const LOCAL_FILE_HEADER_SIZE: u64 = 30;
+
+/// Fields which are set to this value when the real value is in the zip64
+/// extra field.
+pub(crate) const ZIP64_BYTES_THR: u64 = u32::MAX as u64;
+
+pub(crate) const ZIP64_EXTRA_FIELD_TAG: u16 = 0x0001;
+/// Bit 0 of the general purpose flags indicates an encrypted file.
+pub(crate) const FLAG_ENCRYPTED: u16 = 1;
+
+/// Bit 11 of the general purpose flags indicates a UTF-8 file name.
+const FLAG_UTF8: u16 = 1 << 11;
+
+/// Host systems, as found in the upper byte of "version made by".
+const SYSTEM_DOS: u16 = 0;
+const SYSTEM_UNIX: u16 = 3;
+
+/// The central directory of a zip file.
+pub(crate) struct CentralDirectory {
+    /// The entries, in the same order that `zip-rs` indexes them.
+    pub(crate) entries: Vec<CentralDirectoryEntry>,
+    /// The offset at which the central directory starts. No entry data
+    /// should be found beyond this point.
+    pub(crate) start: u64,
+}
+
+/// The central directory record for a single file within a zip file.
+#[derive(Default)]
+pub(crate) struct CentralDirectoryEntry {
+    /// The file name, decoded the same way as `zip-rs` does.
+    pub(crate) name: String,
+    /// The file name as stored, before decoding.
+    pub(crate) raw_name: Vec<u8>,
+    /// Length of the raw file name, which is also the length of the name
+    /// in the local header.
+    pub(crate) name_len: u64,
+    /// The file comment, decoded the same way as the name.
+    pub(crate) comment: String,
+    /// Version of the software which created this entry; the upper byte
+    /// indicates the host system.
+    pub(crate) version_made_by: u16,
+    /// General purpose bit flags.
+    pub(crate) flags: u16,
+    /// Compression method, as stored in the zip file.
+    pub(crate) compression_method: u16,
+    /// MS-DOS format modification date and time.
+    pub(crate) last_modified_date: u16,
+    pub(crate) last_modified_time: u16,
+    pub(crate) crc32: u32,
+    /// Size of the file data.
+    pub(crate) compressed_size: u64,
+    /// Size of the file once decompressed.
+    pub(crate) uncompressed_size: u64,
+    /// Host-system-dependent file attributes.
+    pub(crate) external_attributes: u32,
+    /// Offset of the local file header.
+    pub(crate) header_start: u64,
+    /// Whether any of the sizes or offsets were stored in a zip64 extra
+    /// field.
+    pub(crate) zip64: bool,
+}
+
+/// Information about a file within a zip file, obtained from the
+/// central directory.
+#[derive(Debug, Clone)]
+pub struct ZipEntryInfo {
+    /// The name of the file within the zip file.
+    pub name: String,
+    /// Whether this is a directory.
+    pub is_dir: bool,
+    /// The size of the file once decompressed.
+    pub size: u64,
+    /// The size of the compressed data.
+    pub compressed_size: u64,
+    /// The compression method, as stored in the zip file. See
+    /// [`ZipEntryInfo::compression_method_name`].
+    pub compression_method: u16,
+    /// The CRC-32 of the decompressed data.
+    pub crc32: u32,
+    /// The modification time, in the MS-DOS format stored in every zip file.
+    pub last_modified: zip::DateTime,
+    /// The Unix mode bits, including the file type, if known.
+    pub unix_mode: Option<u32>,
+    /// Whether the file is encrypted.
+    pub encrypted: bool,
+    /// Whether the central directory record uses zip64 extensions for the
+    /// sizes or offset of this file.
+    pub zip64: bool,
+    /// The file comment. Usually empty.
+    pub comment: String,
+    /// The offset within the zip file of the local file header.
+    pub header_start: u64,
+    /// The offset within the zip file at which the file data starts. This
+    /// is only known once the local file header has been read, so is only
+    /// present if obtained from [`crate::UnzipEngine::entries_with_data_start`].
+    pub data_start: Option<u64>,
+}
+
+impl ZipEntryInfo {
+    /// A human-readable name for the compression method.
+    pub fn compression_method_name(&self) -> Cow<'static, str> {
+        match self.compression_method {
+            0 => "stored".into(),
+            8 => "deflated".into(),
+            9 => "deflate64".into(),
+            12 => "bzip2".into(),
+            14 => "lzma".into(),
+            93 => "zstd".into(),
+            99 => "aes".into(),
+            other => format!("unknown({other})").into(),
+        }
+    }
+}
+
+impl From<&CentralDirectoryEntry> for ZipEntryInfo {
+    fn from(entry: &CentralDirectoryEntry) -> Self {
+        Self {
+            name: entry.name.clone(),
+            is_dir: entry.is_dir(),
+            size: entry.uncompressed_size,
+            compressed_size: entry.compressed_size,
+            compression_method: entry.compression_method,
+            crc32: entry.crc32,
+            last_modified: zip::DateTime::from_msdos(
+                entry.last_modified_date,
+                entry.last_modified_time,
+            ),
+            unix_mode: entry.unix_mode(),
+            encrypted: entry.flags & FLAG_ENCRYPTED != 0,
+            zip64: entry.zip64,
+            comment: entry.comment.clone(),
+            header_start: entry.header_start,
+            data_start: None,
+        }
+    }
+}
+
+impl CentralDirectoryEntry {
+    /// Whether this entry is a directory, using the same logic as `zip-rs`.
+    pub(crate) fn is_dir(&self) -> bool {
+        self.name.ends_with('/') || self.name.ends_with('\\')
+    }
+
+    /// The Unix mode bits of this entry, if known. This is the same logic
+    /// as `zip-rs` uses for [`zip::read::ZipFile::unix_mode`].
+    pub(crate) fn unix_mode(&self) -> Option<u32> {
+        const S_IFDIR: u32 = 0o0040000;
+        const S_IFREG: u32 = 0o0100000;
+        if self.external_attributes == 0 {
+            return None;
+        }
+        match self.version_made_by >> 8 {
+            SYSTEM_UNIX => Some(self.external_attributes >> 16),
+            SYSTEM_DOS => {
+                // Interpret MS-DOS directory bit
+                let mut mode = if self.external_attributes & 0x10 != 0 {
+                    S_IFDIR | 0o0775
+                } else {
+                    S_IFREG | 0o0664
+                };
+                if self.external_attributes & 0x01 != 0 {
+                    // Read-only
+                    mode &= 0o0555;
+                }
+                Some(mode)
+            }
+            _ => None,
+        }
+    }
+
+    /// The name of this entry, if it's safe to extract. This is the same
+    /// logic as `zip-rs` uses for [`zip::read::ZipFile::enclosed_name`].
+    pub(crate) fn enclosed_name(&self) -> Option<&Path> {
+        enclosed_name(&self.name)
+    }
+}
+
+/// The name of a file within a zip file as a path relative to the output
+/// directory, or `None` if it's not safe to extract because it's absolute
+/// or would escape the output directory.
+pub(crate) fn enclosed_name(name: &str) -> Option<&Path> {
+    if name.contains('\0') {
+        return None;
+    }
+    let path = Path::new(name);
+    let mut depth = 0usize;
+    for component in path.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir => return None,
+            Component::ParentDir => depth = depth.checked_sub(1)?,
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => (),
+        }
+    }
+    Some(path)
+}
+
+/// Decode a file name or comment the same way as `zip-rs` does.
+pub(crate) fn decode_name(raw: &[u8], flags: u16) -> String {
+    if flags & FLAG_UTF8 != 0 {
+        String::from_utf8_lossy(raw).into_owned()
+    } else {
+        raw.iter().map(|c| cp437_to_char(*c)).collect()
+    }
+}
+
+impl CentralDirectory {
+    /// Read the central directory from a zip file. This reads the end of
+    /// central directory record, then the central directory itself in a
+    /// single read.
+    pub(crate) fn read(mut reader: impl Read + Seek) -> Result<Self> {
+        let file_length = reader.seek(SeekFrom::End(0))?;
+        if file_length < CENTRAL_DIRECTORY_END_SIZE {
+            fail!(Archive, "File is too short to be a zip file");
+        }
+        let tail_len = std::cmp::min(
+            file_length,
+            CENTRAL_DIRECTORY_END_SIZE + u16::MAX as u64 + ZIP64_CENTRAL_DIRECTORY_END_LOCATOR_SIZE,
+        );
+        let tail_start = file_length - tail_len;
+        let tail = read_at(&mut reader, tail_start, tail_len as usize)?;
+        let eocd_offset = (0..=tail.len() - CENTRAL_DIRECTORY_END_SIZE as usize)
+            .rev()
+            .find(|&pos| le_u32(&tail[pos..]) == CENTRAL_DIRECTORY_END_SIGNATURE)
+            .context("Could not find central directory end")?;
+        let eocd = &tail[eocd_offset..];
+        let eocd_pos = tail_start + eocd_offset as u64;
+        let mut number_of_files = le_u16(&eocd[10..]) as u64;
+        let mut directory_size = le_u32(&eocd[12..]) as u64;
+        let mut directory_offset = le_u32(&eocd[16..]) as u64;
+
+        let locator_offset =
+            eocd_offset.checked_sub(ZIP64_CENTRAL_DIRECTORY_END_LOCATOR_SIZE as usize);
+        let locator = locator_offset
+            .map(|offset| &tail[offset..])
+            .filter(|locator| le_u32(locator) == ZIP64_CENTRAL_DIRECTORY_END_LOCATOR_SIGNATURE);
+        let directory_end = match locator {
+            None => eocd_pos,
+            Some(locator) => {
+                let zip64_eocd_pos = le_u64(&locator[8..]);
+                let zip64_eocd = read_at(
+                    &mut reader,
+                    zip64_eocd_pos,
+                    ZIP64_CENTRAL_DIRECTORY_END_SIZE,
+                )?;
+                if le_u32(&zip64_eocd) != ZIP64_CENTRAL_DIRECTORY_END_SIGNATURE {
+                    fail!(Archive, "Invalid zip64 central directory end");
+                }
+                number_of_files = le_u64(&zip64_eocd[32..]);
+                directory_size = le_u64(&zip64_eocd[40..]);
+                directory_offset = le_u64(&zip64_eocd[48..]);
+                zip64_eocd_pos
+            }
+        };
+        // Some zip files have data prepended to them, in which case all the
+        // recorded offsets are too small.
+        let archive_offset = directory_end
+            .checked_sub(directory_size)
+            .and_then(|x| x.checked_sub(directory_offset))
+            .context("Invalid central directory size or offset")?;
+        let Some(start) = directory_offset.checked_add(archive_offset) else {
+            fail!(Archive, "Invalid central directory offset");
+        };
+        let directory = read_at(&mut reader, start, directory_size as usize)?;
+
+        let mut entries = Vec::new();
+        let mut remaining = &directory[..];
+        for _ in 0..number_of_files {
+            let (entry, rest) = parse_entry(remaining, archive_offset)?;
+            entries.push(entry);
+            remaining = rest;
+        }
+        Ok(Self { entries, start })
+    }
+}
+
+/// Parse a single central directory header, returning the entry and
+/// the remaining data.
+fn parse_entry(data: &[u8], archive_offset: u64) -> Result<(CentralDirectoryEntry, &[u8])> {
+    if data.len() < CENTRAL_DIRECTORY_HEADER_SIZE
+        || le_u32(data) != CENTRAL_DIRECTORY_HEADER_SIGNATURE
+    {
+        fail!(Archive, "Invalid central directory header");
+    }
+    let version_made_by = le_u16(&data[4..]);
+    let flags = le_u16(&data[8..]);
+    let compression_method = le_u16(&data[10..]);
+    let last_modified_time = le_u16(&data[12..]);
+    let last_modified_date = le_u16(&data[14..]);
+    let crc32 = le_u32(&data[16..]);
+    let mut compressed_size = le_u32(&data[20..]) as u64;
+    let mut uncompressed_size = le_u32(&data[24..]) as u64;
+    let name_len = le_u16(&data[28..]) as usize;
+    let extra_len = le_u16(&data[30..]) as usize;
+    let comment_len = le_u16(&data[32..]) as usize;
+    let external_attributes = le_u32(&data[38..]);
+    let mut header_start = le_u32(&data[42..]) as u64;
+    let mut zip64 = false;
+    let total_len = CENTRAL_DIRECTORY_HEADER_SIZE + name_len + extra_len + comment_len;
+    if data.len() < total_len {
+        fail!(Archive, "Central directory header is truncated");
+    }
+    let name_raw = &data[CENTRAL_DIRECTORY_HEADER_SIZE..CENTRAL_DIRECTORY_HEADER_SIZE + name_len];
+    let extra = &data[CENTRAL_DIRECTORY_HEADER_SIZE + name_len
+        ..CENTRAL_DIRECTORY_HEADER_SIZE + name_len + extra_len];
+    for (tag, mut field) in extra_fields(extra) {
+        if tag == ZIP64_EXTRA_FIELD_TAG {
+            for value in [
+                &mut uncompressed_size,
+                &mut compressed_size,
+                &mut header_start,
+            ] {
+                if *value == ZIP64_BYTES_THR && field.len() >= 8 {
+                    *value = le_u64(field);
+                    field = &field[8..];
+                    zip64 = true;
+                }
+            }
+        }
+    }
+    let Some(header_start) = header_start.checked_add(archive_offset) else {
+        fail!(Archive, "Invalid local header offset");
+    };
+    let comment_raw = &data[total_len - comment_len..total_len];
+    let decode = |raw: &[u8]| decode_name(raw, flags);
+    Ok((
+        CentralDirectoryEntry {
+            name: decode(name_raw),
+            raw_name: name_raw.to_vec(),
+            name_len: name_len as u64,
+            comment: decode(comment_raw),
+            version_made_by,
+            flags,
+            compression_method,
+            last_modified_date,
+            last_modified_time,
+            crc32,
+            compressed_size,
+            uncompressed_size,
+            external_attributes,
+            header_start,
+            zip64,
+        },
+        &data[total_len..],
+    ))
+}
+
+/// Iterate over the (tag, data) pairs of an extra field block. Truncated
+/// fields are ignored.
+pub(crate) fn extra_fields(mut extra: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if extra.len() < 4 {
+            return None;
+        }
+        let tag = le_u16(extra);
+        let len = le_u16(&extra[2..]) as usize;
+        let field = extra.get(4..4 + len)?;
+        extra = &extra[4 + len..];
+        Some((tag, field))
+    })
+}
+
+fn read_at(reader: &mut (impl Read + Seek), pos: u64, len: usize) -> Result<Vec<u8>> {
+    reader.seek(SeekFrom::Start(pos))?;
+    let mut buf = vec![0u8; len];
+    reader
+        .read_exact(&mut buf)
+        .context("Unable to read zip central directory")?;
+    Ok(buf)
+}
+
+pub(crate) fn le_u16(data: &[u8]) -> u16 {
+    u16::from_le_bytes(data[..2].try_into().unwrap())
+}
+
+pub(crate) fn le_u32(data: &[u8]) -> u32 {
+    u32::from_le_bytes(data[..4].try_into().unwrap())
+}
+
+pub(crate) fn le_u64(data: &[u8]) -> u64 {
+    u64::from_le_bytes(data[..8].try_into().unwrap())
+}
+
+/// Convert a byte in IBM codepage 437 to a character.
+fn cp437_to_char(c: u8) -> char {
+    const HIGH_HALF: [char; 128] = [
+        'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', //
+        'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', //
+        'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', //
+        '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐', //
+        '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧', //
+        '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀', //
+        'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', //
+        '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+    ];
+    if c < 0x80 {
+        c as char
+    } else {
+        HIGH_HALF[(c - 0x80) as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+    use test_log::test;
+    use zip::{write::FileOptions, ZipArchive, ZipWriter};
+
+    use super::{CentralDirectory, ZipEntryInfo};
+
+    #[test]
+    fn test_matches_zip_rs() {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.add_directory("test/", Default::default()).unwrap();
+        let options = FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        zip.start_file("test/a.txt", options).unwrap();
+        zip.write_all(&[b'a'; 1000]).unwrap();
+        zip.start_file("b\u{e9}.txt", options).unwrap();
+        zip.write_all(b"Contents of B\n").unwrap();
+        zip.start_file("../evil.txt", options).unwrap();
+        // Some junk at the start, which should be reflected in the offsets.
+        let mut zip_data = b"prefix".to_vec();
+        zip_data.extend(zip.finish().unwrap().into_inner());
+
+        let central_directory = CentralDirectory::read(Cursor::new(&zip_data)).unwrap();
+        let mut archive = ZipArchive::new(Cursor::new(&zip_data)).unwrap();
+        assert_eq!(central_directory.entries.len(), archive.len());
+        for (i, entry) in central_directory.entries.iter().enumerate() {
+            let file = archive.by_index(i).unwrap();
+            assert_eq!(entry.name, file.name());
+            assert_eq!(entry.enclosed_name(), file.enclosed_name());
+            assert_eq!(entry.header_start, file.header_start());
+            assert_eq!(entry.compressed_size, file.compressed_size());
+            assert_eq!(entry.uncompressed_size, file.size());
+            assert_eq!(entry.crc32, file.crc32());
+            assert_eq!(entry.is_dir(), file.is_dir());
+            assert_eq!(entry.unix_mode(), file.unix_mode());
+            assert_eq!(entry.comment, file.comment());
+            let info = ZipEntryInfo::from(entry);
+            assert_eq!(
+                info.last_modified.datepart(),
+                file.last_modified().datepart()
+            );
+            assert_eq!(
+                info.last_modified.timepart(),
+                file.last_modified().timepart()
+            );
+            assert_eq!(
+                info.compression_method_name(),
+                file.compression().to_string().to_lowercase()
+            );
+            assert!(entry.header_start < central_directory.start);
+        }
+    }
+
+    #[test]
+    fn test_not_a_zip() {
+        assert!(CentralDirectory::read(Cursor::new(b"not a zip")).is_err());
+        assert!(CentralDirectory::read(Cursor::new(vec![0u8; 100])).is_err());
+    }
+}
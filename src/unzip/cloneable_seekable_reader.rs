@@ -124,9 +124,67 @@ impl HasLength for File {
     }
 }
 
+/// A [`File`] which refers to its underlying file by reference count, and
+/// thus can be cloned cheaply. Unlike [`CloneableSeekableReader`], clones
+/// read from the file independently, without locking, using
+/// [`positioned_io`]. The file is closed once every clone has been dropped.
+#[derive(Clone)]
+pub(crate) struct SharedFile(Arc<File>);
+
+impl SharedFile {
+    pub(crate) fn new(file: File) -> Self {
+        Self(Arc::new(file))
+    }
+}
+
+impl positioned_io::ReadAt for SharedFile {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.as_ref().read_at(pos, buf)
+    }
+}
+
+impl positioned_io::Size for SharedFile {
+    fn size(&self) -> std::io::Result<Option<u64>> {
+        self.0.as_ref().size()
+    }
+}
+
+/// A [`Read`] and [`Seek`] whose length we determine up-front by seeking to
+/// the end, for streams which don't otherwise know their length.
+pub(crate) struct ReaderWithLength<R: Read + Seek> {
+    inner: R,
+    len: u64,
+}
+
+impl<R: Read + Seek> ReaderWithLength<R> {
+    pub(crate) fn new(mut inner: R) -> std::io::Result<Self> {
+        let len = inner.seek(SeekFrom::End(0))?;
+        inner.rewind()?;
+        Ok(Self { inner, len })
+    }
+}
+
+impl<R: Read + Seek> HasLength for ReaderWithLength<R> {
+    fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+impl<R: Read + Seek> Read for ReaderWithLength<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<R: Read + Seek> Seek for ReaderWithLength<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::{CloneableSeekableReader, HasLength};
+    use super::{CloneableSeekableReader, HasLength, ReaderWithLength};
     use std::io::{Cursor, Read, Seek, SeekFrom};
     use test_log::test;
 
@@ -159,4 +217,15 @@ mod test {
         assert_eq!(out[1], 9);
         assert!(reader.read_exact(&mut out).is_err());
     }
+
+    #[test]
+    fn test_reader_with_length() {
+        let mut buf = Cursor::new(vec![0, 1, 2, 3, 4]);
+        buf.set_position(3);
+        let mut reader = ReaderWithLength::new(buf).unwrap();
+        assert_eq!(reader.len(), 5);
+        let mut out = vec![0; 2];
+        assert!(reader.read_exact(&mut out).is_ok());
+        assert_eq!(out, [0, 1]);
+    }
 }
@@ -0,0 +1,147 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use anyhow::Result;
+use thiserror::Error;
+
+use super::{
+    central_directory::CentralDirectoryEntry,
+    error::ClassifiedError,
+    stream_reader::{EntryTrailer, LocalFileHeader, ZipStreamReader},
+    FailureKind,
+};
+
+/// A way in which a file's local header disagrees with its central
+/// directory record. Zip files like this can make different unzip tools
+/// see different files, so are refused in strict mode.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum HeaderMismatch {
+    /// The file names differ, as stored. Names which only decode
+    /// differently, because one header sets the UTF-8 flag and the other
+    /// doesn't, aren't a mismatch.
+    #[error("Local header names {local:?} but central directory names {central:?}")]
+    Name {
+        /// The name in the central directory, decoded.
+        central: String,
+        /// The name in the local header, decoded.
+        local: String,
+    },
+    /// The compression methods differ.
+    #[error("Local header has compression method {local} but central directory has {central}")]
+    CompressionMethod {
+        /// The compression method in the central directory.
+        central: u16,
+        /// The compression method in the local header.
+        local: u16,
+    },
+    /// The compressed sizes differ.
+    #[error("Local header has compressed size {local} but central directory has {central}")]
+    CompressedSize {
+        /// The compressed size in the central directory.
+        central: u64,
+        /// The compressed size in the local header or data descriptor.
+        local: u64,
+    },
+    /// The uncompressed sizes differ.
+    #[error("Local header has uncompressed size {local} but central directory has {central}")]
+    UncompressedSize {
+        /// The uncompressed size in the central directory.
+        central: u64,
+        /// The uncompressed size in the local header or data descriptor.
+        local: u64,
+    },
+    /// The CRCs differ.
+    #[error("Local header has CRC {local:08x} but central directory has {central:08x}")]
+    Crc32 {
+        /// The CRC in the central directory.
+        central: u32,
+        /// The CRC in the local header or data descriptor.
+        local: u32,
+    },
+}
+
+/// Read the local header of a file, and check that it agrees with the
+/// file's central directory record. Any disagreement is returned as a
+/// [`HeaderMismatch`].
+pub(crate) fn check_local_header(
+    mut reader: impl Read + Seek,
+    entry: &CentralDirectoryEntry,
+) -> Result<()> {
+    reader.seek(SeekFrom::Start(entry.header_start))?;
+    let header = ZipStreamReader::new(reader).next_header()?.ok_or_else(|| {
+        ClassifiedError::new(
+            FailureKind::Archive,
+            format!("No local header found at offset {}", entry.header_start),
+        )
+    })?;
+    check_name(entry, &header)?;
+    if header.compression_method != entry.compression_method {
+        return Err(HeaderMismatch::CompressionMethod {
+            central: entry.compression_method,
+            local: header.compression_method,
+        }
+        .into());
+    }
+    // Otherwise, these are in a data descriptor after the file data.
+    if !header.has_data_descriptor() {
+        check_trailer(
+            entry,
+            &EntryTrailer {
+                crc32: header.crc32,
+                compressed_size: header.compressed_size,
+                uncompressed_size: header.uncompressed_size,
+            },
+        )?;
+    }
+    Ok(())
+}
+
+/// Check that a file's local header names the same file as its central
+/// directory record. The raw names are compared, since the UTF-8 flag
+/// needn't be set in both headers.
+pub(crate) fn check_name(
+    entry: &CentralDirectoryEntry,
+    header: &LocalFileHeader,
+) -> Result<(), HeaderMismatch> {
+    if header.raw_name != entry.raw_name {
+        return Err(HeaderMismatch::Name {
+            central: entry.name.clone(),
+            local: header.name.clone(),
+        });
+    }
+    Ok(())
+}
+
+/// Check that the CRC and sizes of a file, from its local header or data
+/// descriptor, agree with its central directory record.
+pub(crate) fn check_trailer(
+    entry: &CentralDirectoryEntry,
+    trailer: &EntryTrailer,
+) -> Result<(), HeaderMismatch> {
+    if trailer.compressed_size != entry.compressed_size {
+        return Err(HeaderMismatch::CompressedSize {
+            central: entry.compressed_size,
+            local: trailer.compressed_size,
+        });
+    }
+    if trailer.uncompressed_size != entry.uncompressed_size {
+        return Err(HeaderMismatch::UncompressedSize {
+            central: entry.uncompressed_size,
+            local: trailer.uncompressed_size,
+        });
+    }
+    if trailer.crc32 != entry.crc32 {
+        return Err(HeaderMismatch::Crc32 {
+            central: entry.crc32,
+            local: trailer.crc32,
+        });
+    }
+    Ok(())
+}
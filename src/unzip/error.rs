@@ -0,0 +1,320 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::error::Error as StdError;
+
+use thiserror::Error;
+use zip::result::ZipError;
+
+use super::{
+    consistency::HeaderMismatch, http_range_reader, report::EntryFailures, seekable_http_reader,
+    FailureKind,
+};
+
+/// Errors returned by the public API. Each variant holds the full chain of
+/// errors which led to it, available through [`std::error::Error::source`].
+/// More variants may be added in future.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// Fetching the zip file over HTTP failed.
+    #[error(transparent)]
+    Transport(Box<dyn StdError + Send + Sync>),
+    /// The zip file is malformed or corrupt.
+    #[error(transparent)]
+    Archive(Box<dyn StdError + Send + Sync>),
+    /// The zip file uses a feature which isn't supported, such as
+    /// encryption or an unusual compression method.
+    #[error(transparent)]
+    Unsupported(Box<dyn StdError + Send + Sync>),
+    /// Reading or writing local files failed, for example because of
+    /// permissions or a full disk.
+    #[error(transparent)]
+    Filesystem(Box<dyn StdError + Send + Sync>),
+    /// We refused to do something unsafe, such as writing outside the
+    /// output directory or extracting overlapping entries.
+    #[error(transparent)]
+    Security(Box<dyn StdError + Send + Sync>),
+    /// One of the [`super::ExtractionLimits`] was exceeded, or there isn't
+    /// enough free space.
+    #[error(transparent)]
+    Limit(Box<dyn StdError + Send + Sync>),
+    /// Some files couldn't be extracted, though others may have been.
+    #[error(transparent)]
+    Entries(EntryFailures),
+    /// The extraction was cancelled using a
+    /// [`super::CancellationToken`].
+    #[error("Extraction was cancelled")]
+    Cancelled,
+    /// Anything else, such as an invalid filename pattern.
+    #[error(transparent)]
+    Other(Box<dyn StdError + Send + Sync>),
+}
+
+impl Error {
+    /// What kind of failure this represents, for an error which has passed
+    /// back through our own code, such as one from an
+    /// [`super::ExtractionSink`].
+    fn kind(&self) -> Option<FailureKind> {
+        match self {
+            Self::Transport(_) => Some(FailureKind::Transport),
+            Self::Archive(_) => Some(FailureKind::Archive),
+            Self::Unsupported(_) => Some(FailureKind::Unsupported),
+            Self::Filesystem(error) => {
+                let error: &(dyn StdError + 'static) = &**error;
+                let kind = std::iter::successors(Some(error), |&e| e.source())
+                    .find_map(|e| e.downcast_ref::<std::io::Error>())
+                    .map_or(std::io::ErrorKind::Other, std::io::Error::kind);
+                Some(FailureKind::Filesystem(kind))
+            }
+            Self::Security(_) => Some(FailureKind::Security),
+            Self::Limit(_) => Some(FailureKind::Limit),
+            Self::Cancelled => Some(FailureKind::Cancelled),
+            Self::Entries(_) | Self::Other(_) => None,
+        }
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(error: anyhow::Error) -> Self {
+        let error = match error.downcast::<EntryFailures>() {
+            Ok(failures) => return Self::Entries(failures),
+            Err(error) => error,
+        };
+        match classify(&error) {
+            FailureKind::Transport => Self::Transport(error.into()),
+            FailureKind::Archive => Self::Archive(error.into()),
+            FailureKind::Unsupported => Self::Unsupported(error.into()),
+            FailureKind::Filesystem(_) => Self::Filesystem(error.into()),
+            FailureKind::Security => Self::Security(error.into()),
+            FailureKind::Limit => Self::Limit(error.into()),
+            FailureKind::Cancelled => Self::Cancelled,
+            FailureKind::Other => Self::Other(error.into()),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        anyhow::Error::from(error).into()
+    }
+}
+
+impl From<ZipError> for Error {
+    fn from(error: ZipError) -> Self {
+        anyhow::Error::from(error).into()
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(error: reqwest::Error) -> Self {
+        anyhow::Error::from(error).into()
+    }
+}
+
+/// An error which was classified where it arose, for errors which don't
+/// otherwise have a type we can recognize.
+#[derive(Debug, Error)]
+#[error("{message}")]
+pub(crate) struct ClassifiedError {
+    kind: FailureKind,
+    message: String,
+}
+
+impl ClassifiedError {
+    pub(crate) fn new(kind: FailureKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+/// Like [`anyhow::bail`], but classifies the error as the given
+/// [`FailureKind`].
+macro_rules! fail {
+    ($kind:ident, $($arg:tt)+) => {
+        return Err($crate::unzip::error::ClassifiedError::new(
+            $crate::unzip::FailureKind::$kind,
+            format!($($arg)+),
+        )
+        .into())
+    };
+}
+
+pub(crate) use fail;
+
+/// Work out what kind of failure an error represents, from the first error
+/// in its chain which we recognize.
+pub(crate) fn classify(error: &anyhow::Error) -> FailureKind {
+    error
+        .chain()
+        .find_map(classify_one)
+        .unwrap_or(FailureKind::Other)
+}
+
+fn classify_one(error: &(dyn StdError + 'static)) -> Option<FailureKind> {
+    if let Some(error) = error.downcast_ref::<ClassifiedError>() {
+        Some(error.kind)
+    } else if let Some(error) = error.downcast_ref::<Error>() {
+        error.kind()
+    } else if error.is::<HeaderMismatch>() {
+        Some(FailureKind::Archive)
+    } else if error.is::<reqwest::Error>()
+        || error.is::<http_range_reader::Error>()
+        || error.is::<seekable_http_reader::Error>()
+        || error.is::<seekable_http_reader::Aborted>()
+    {
+        Some(FailureKind::Transport)
+    } else if let Some(error) = error.downcast_ref::<ZipError>() {
+        match error {
+            ZipError::Io(error) => Some(classify_io(error)),
+            ZipError::UnsupportedArchive(_) => Some(FailureKind::Unsupported),
+            _ => Some(FailureKind::Archive),
+        }
+    } else {
+        error.downcast_ref::<std::io::Error>().map(classify_io)
+    }
+}
+
+/// Whether an error arose only because we aborted the download, rather than
+/// because of anything wrong with the file being read.
+pub(crate) fn is_aborted(error: &anyhow::Error) -> bool {
+    error.chain().any(is_aborted_one)
+}
+
+fn is_aborted_one(error: &(dyn StdError + 'static)) -> bool {
+    if let Some(error) = error.downcast_ref::<std::io::Error>() {
+        error.get_ref().is_some_and(|inner| is_aborted_one(inner))
+    } else {
+        error.is::<seekable_http_reader::Aborted>()
+    }
+}
+
+fn classify_io(error: &std::io::Error) -> FailureKind {
+    // Errors from HTTP streams, and from our own readers, arrive wrapped in
+    // I/O errors.
+    if let Some(kind) = error.get_ref().and_then(|inner| classify_one(inner)) {
+        return kind;
+    }
+    // zip-rs reports a CRC mismatch as an I/O error.
+    if error.kind() == std::io::ErrorKind::InvalidData || error.to_string() == "Invalid checksum" {
+        FailureKind::Archive
+    } else {
+        FailureKind::Filesystem(error.kind())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        error::Error as _,
+        io::{Cursor, Read, Write},
+    };
+    use test_log::test;
+    use zip::{result::ZipError, write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+    use super::{classify, is_aborted, ClassifiedError, Error};
+    use crate::unzip::{seekable_http_reader, EntryFailure, EntryFailures, FailureKind};
+
+    #[test]
+    fn test_classify() {
+        let denied = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        assert_eq!(
+            classify(&anyhow::Error::new(denied).context("Failed to create file")),
+            FailureKind::Filesystem(std::io::ErrorKind::PermissionDenied)
+        );
+        let checksum = std::io::Error::other("Invalid checksum");
+        assert_eq!(
+            classify(&anyhow::Error::new(ZipError::Io(checksum))),
+            FailureKind::Archive
+        );
+        let limit = ClassifiedError::new(FailureKind::Limit, "Too big");
+        assert_eq!(
+            classify(&anyhow::Error::new(std::io::Error::other(limit)).context("oops")),
+            FailureKind::Limit
+        );
+        assert_eq!(
+            classify(&anyhow::Error::new(ZipError::UnsupportedArchive(
+                ZipError::PASSWORD_REQUIRED
+            ))),
+            FailureKind::Unsupported
+        );
+        assert_eq!(classify(&anyhow::anyhow!("Huh")), FailureKind::Other);
+        // Errors from sinks keep their kind.
+        let denied = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        assert_eq!(
+            classify(&anyhow::Error::new(Error::Filesystem(Box::new(denied)))),
+            FailureKind::Filesystem(std::io::ErrorKind::PermissionDenied)
+        );
+        let limit = Error::Limit("Too big".into());
+        assert_eq!(
+            classify(&anyhow::Error::new(limit).context("Failed to create file")),
+            FailureKind::Limit
+        );
+    }
+
+    #[test]
+    fn test_classify_corrupt_data_from_zip_rs() {
+        // zip-rs 0.6 reports a CRC mismatch as an I/O error of kind Other,
+        // so we recognize it by its message. If an upgrade changes that,
+        // corrupt files would be misreported as filesystem failures.
+        let mut zip_data = Cursor::new(Vec::new());
+        let mut zip = ZipWriter::new(&mut zip_data);
+        let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+        zip.start_file("a.txt", options).unwrap();
+        zip.write_all(b"Contents of A").unwrap();
+        zip.finish().unwrap();
+        drop(zip);
+        let mut zip_data = zip_data.into_inner();
+        let offset = zip_data
+            .windows(8)
+            .position(|window| window == b"Contents")
+            .unwrap();
+        zip_data[offset] = b'X';
+        let mut archive = ZipArchive::new(Cursor::new(zip_data)).unwrap();
+        let error = archive
+            .by_index(0)
+            .unwrap()
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Invalid checksum");
+        assert_eq!(classify(&anyhow::Error::new(error)), FailureKind::Archive);
+    }
+
+    #[test]
+    fn test_is_aborted() {
+        let aborted = std::io::Error::other(seekable_http_reader::Aborted);
+        let aborted = anyhow::Error::new(ZipError::Io(aborted)).context("Failed to extract");
+        assert!(is_aborted(&aborted));
+        assert_eq!(classify(&aborted), FailureKind::Transport);
+        let checksum = std::io::Error::other("Invalid checksum");
+        assert!(!is_aborted(&anyhow::Error::new(ZipError::Io(checksum))));
+    }
+
+    #[test]
+    fn test_public_error() {
+        let error = Error::from(
+            anyhow::Error::new(ZipError::InvalidArchive("Bad header"))
+                .context("Failed to read central directory"),
+        );
+        assert!(matches!(error, Error::Archive(_)));
+        assert_eq!(error.to_string(), "Failed to read central directory");
+        assert_eq!(
+            error.source().map(ToString::to_string).as_deref(),
+            Some("invalid Zip archive: Bad header")
+        );
+        let failures = EntryFailures {
+            failures: vec![EntryFailure::new("a.txt", Some(0), anyhow::anyhow!("A"))],
+        };
+        assert!(matches!(
+            Error::from(anyhow::Error::new(failures)),
+            Error::Entries(_)
+        ));
+    }
+}
@@ -0,0 +1,107 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use super::Error;
+
+/// A pattern which can be matched against the names of files within
+/// a zip file.
+#[derive(Debug, Clone)]
+pub enum FilenamePattern {
+    /// A shell-style glob such as `docs/*.md`. As with Info-ZIP `unzip`,
+    /// wildcards may match across `/` separators.
+    Glob(glob::Pattern),
+    /// A regular expression. This is unanchored, so use `^` and `$` if you
+    /// want to match the whole name.
+    Regex(regex::Regex),
+}
+
+impl FilenamePattern {
+    /// Create a pattern from a shell-style glob.
+    pub fn glob(pattern: &str) -> Result<Self, Error> {
+        Ok(Self::Glob(glob::Pattern::new(pattern).with_context(
+            || format!("Invalid glob pattern {pattern}"),
+        )?))
+    }
+
+    /// Create a pattern from a regular expression.
+    pub fn regex(pattern: &str) -> Result<Self, Error> {
+        Ok(Self::Regex(regex::Regex::new(pattern).with_context(
+            || format!("Invalid regular expression {pattern}"),
+        )?))
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Self::Glob(pattern) => pattern.matches(name),
+            Self::Regex(regex) => regex.is_match(name),
+        }
+    }
+}
+
+/// Selects which files within a zip file should be extracted. A file is
+/// extracted if it matches any of the `include` patterns (or there are
+/// none) and matches none of the `exclude` patterns. Patterns are matched
+/// against the name of the file as it would be extracted, relative to the
+/// output directory.
+#[derive(Debug, Clone, Default)]
+pub struct FilenameFilter {
+    /// Patterns of which a file must match at least one to be extracted.
+    /// If this is empty, all files are included.
+    pub include: Vec<FilenamePattern>,
+    /// Patterns which, if matched, prevent a file being extracted.
+    pub exclude: Vec<FilenamePattern>,
+}
+
+impl FilenameFilter {
+    /// Whether a file of this name should be extracted.
+    pub(crate) fn should_extract(&self, name: &Path) -> bool {
+        let name = name.to_string_lossy();
+        (self.include.is_empty() || self.include.iter().any(|p| p.matches(&name)))
+            && !self.exclude.iter().any(|p| p.matches(&name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use test_log::test;
+
+    use super::{FilenameFilter, FilenamePattern};
+
+    #[test]
+    fn test_empty_filter() {
+        let filter = FilenameFilter::default();
+        assert!(filter.should_extract(Path::new("a.txt")));
+        assert!(filter.should_extract(Path::new("test/a.txt")));
+    }
+
+    #[test]
+    fn test_include_and_exclude() {
+        let filter = FilenameFilter {
+            include: vec![
+                FilenamePattern::glob("test/*").unwrap(),
+                FilenamePattern::regex(r"^b\.").unwrap(),
+            ],
+            exclude: vec![FilenamePattern::glob("*c.txt").unwrap()],
+        };
+        assert!(filter.should_extract(Path::new("test/a.txt")));
+        assert!(filter.should_extract(Path::new("b.txt")));
+        assert!(!filter.should_extract(Path::new("test/c.txt")));
+        assert!(!filter.should_extract(Path::new("d.txt")));
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        assert!(FilenamePattern::glob("a[").is_err());
+        assert!(FilenamePattern::regex("a(").is_err());
+    }
+}
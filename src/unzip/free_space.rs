@@ -0,0 +1,60 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use super::error::fail;
+
+/// Check that the filesystem containing `output_directory` has room for
+/// `needed` bytes. The output directory needn't exist yet, in which case
+/// we check the filesystem of its nearest existing ancestor.
+///
+/// This doesn't allow for space freed by overwriting existing files, nor
+/// for filesystem overheads, so it's only an approximation.
+pub(crate) fn check_free_space(output_directory: &Path, needed: u64) -> Result<()> {
+    let output_directory = if output_directory.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        output_directory
+    };
+    let existing = output_directory
+        .ancestors()
+        .find(|dir| dir.exists())
+        .unwrap_or(Path::new("."));
+    let available = fs2::available_space(existing)
+        .with_context(|| format!("Failed to find free space in {}", existing.display()))?;
+    if needed > available {
+        fail!(
+            Limit,
+            "Not enough free space in {}: extraction needs {} bytes but only {} are available",
+            output_directory.display(),
+            needed,
+            available
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+    use test_log::test;
+
+    use super::check_free_space;
+
+    #[test]
+    fn test_check_free_space() {
+        let td = tempdir().unwrap();
+        let outdir = td.path().join("not/yet/created");
+        check_free_space(&outdir, 0).unwrap();
+        let message = check_free_space(&outdir, u64::MAX).unwrap_err().to_string();
+        assert!(message.contains(&format!("needs {} bytes", u64::MAX)));
+    }
+}
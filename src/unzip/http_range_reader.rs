@@ -6,9 +6,12 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::{cmp::min, io::Read};
+use std::{cmp::min, io::Read, ops::Range};
 
-use reqwest::blocking::{Client, Response};
+use reqwest::{
+    blocking::{Client, Response},
+    header::HeaderMap,
+};
 use thiserror::Error;
 
 /// Errors that may be returned by a [`RangeFetcher`].
@@ -43,7 +46,8 @@ impl RangeFetcher {
     pub(crate) fn new(uri: String) -> Result<Self, Error> {
         let client = reqwest::blocking::Client::new();
         let response = client.head(&uri).send().map_err(Error::HttpHead)?;
-        let content_length = content_length_via_headers(&response).ok_or(Error::NoContentLength)?;
+        let content_length =
+            content_length_via_headers(response.headers()).ok_or(Error::NoContentLength)?;
         if content_length == 0 {
             return Err(Error::EmptyContentLength);
         }
@@ -68,17 +72,20 @@ impl RangeFetcher {
         self.accept_ranges
     }
 
-    /// Return a [`Read`] for this resource starting from the given offset.
+    /// Return a [`Read`] for the given range of this resource.
     /// If the resource supports HTTP ranges, this will start reading from
-    /// the server at that point; otherwise, it will read from the outset
-    /// of the resource but discard bytes before that point. (Clearly that
+    /// the server at the start of the range; otherwise, it will read from the
+    /// outset of the resource but discard bytes before that point. (Clearly that
     /// can be expensive if you only care about a few bytes later in a
-    /// resource.)
-    pub(crate) fn fetch_range(&self, offset: u64) -> Result<Response, Error> {
-        log::info!("Fetch range 0x{:x}", offset);
+    /// resource.) The server may send data beyond the end of the range, so
+    /// callers should not read beyond it.
+    pub(crate) fn fetch_range(&self, range: Range<u64>) -> Result<Response, Error> {
+        log::info!("Fetch range 0x{:x}-0x{:x}", range.start, range.end);
+        let offset = range.start;
         let mut builder = self.client.get(&self.uri);
         if self.accept_ranges {
-            let range_header = format!("bytes={}-{}", offset, self.len());
+            // HTTP ranges are inclusive of the last byte.
+            let range_header = format!("bytes={}-{}", offset, range.end.saturating_sub(1));
             builder = builder.header(reqwest::header::RANGE, range_header);
         }
         let mut response = builder.send().map_err(Error::HttpGet)?;
@@ -99,9 +106,8 @@ impl RangeFetcher {
 
 /// Determine the `Content-Length` header. `reqwest` says it does this, but
 /// doesn't: https://github.com/seanmonstar/reqwest/issues/1136
-fn content_length_via_headers(response: &Response) -> Option<u64> {
-    response
-        .headers()
+pub(crate) fn content_length_via_headers(headers: &HeaderMap) -> Option<u64> {
+    headers
         .get(reqwest::header::CONTENT_LENGTH)
         .and_then(|hv| hv.to_str().ok())
         .and_then(|s| s.parse::<u64>().ok())
@@ -150,16 +156,22 @@ mod tests {
                 .respond_with(status_code(200).body(body))
         });
         assert_eq!(accept_ranges, range_fetcher.accepts_ranges());
-        let mut resp = range_fetcher.fetch_range(0u64).unwrap();
+        let mut resp = range_fetcher.fetch_range(0..10).unwrap();
         let mut throwaway = [0u8; 10];
         resp.read_exact(&mut throwaway).unwrap();
         assert_eq!(std::str::from_utf8(&throwaway).unwrap(), "0123456789");
 
         // Test read only a range
-        let mut resp = range_fetcher.fetch_range(4u64).unwrap();
+        let mut resp = range_fetcher.fetch_range(4..10).unwrap();
         let mut throwaway = [0u8; 6];
         resp.read_exact(&mut throwaway).unwrap();
         assert_eq!(std::str::from_utf8(&throwaway).unwrap(), "456789");
+
+        // Test read a bounded range
+        let mut resp = range_fetcher.fetch_range(2..5).unwrap();
+        let mut throwaway = [0u8; 3];
+        resp.read_exact(&mut throwaway).unwrap();
+        assert_eq!(std::str::from_utf8(&throwaway).unwrap(), "234");
     }
 
     #[test]
@@ -0,0 +1,265 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    io::Read,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::{Context, Result};
+
+use super::{
+    central_directory::CentralDirectory,
+    error::{fail, ClassifiedError},
+    FailureKind,
+};
+
+/// Limits on what we're prepared to extract, to defend against zip bombs.
+/// Each limit is checked against the central directory before extraction
+/// starts, and also enforced as data is decompressed, since the sizes in
+/// the zip file may not be truthful. By default, there are no limits,
+/// except when extracting into memory, where [`ExtractionLimits::IN_MEMORY`]
+/// fills in any which aren't set.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExtractionLimits {
+    /// The maximum total uncompressed size of the files we extract.
+    pub max_total_size: Option<u64>,
+    /// The maximum number of entries in the zip file, including those
+    /// we don't extract.
+    pub max_entries: Option<u64>,
+    /// The maximum uncompressed size of any single file.
+    pub max_file_size: Option<u64>,
+    /// The maximum ratio of any file's uncompressed size to its compressed
+    /// size.
+    pub max_compression_ratio: Option<u64>,
+}
+
+impl ExtractionLimits {
+    /// The limits applied by [`super::UnzipEngine::extract_to_memory`] when
+    /// none are set, since everything extracted is held in memory at once:
+    /// 1 GiB in total, and no file more than 100 times its compressed size.
+    pub const IN_MEMORY: Self = Self {
+        max_total_size: Some(1024 * 1024 * 1024),
+        max_entries: None,
+        max_file_size: None,
+        max_compression_ratio: Some(100),
+    };
+
+    /// These limits, with the total size and compression ratio from
+    /// [`ExtractionLimits::IN_MEMORY`] wherever they aren't set.
+    pub(crate) fn or_in_memory_defaults(self) -> Self {
+        Self {
+            max_total_size: self.max_total_size.or(Self::IN_MEMORY.max_total_size),
+            max_compression_ratio: self
+                .max_compression_ratio
+                .or(Self::IN_MEMORY.max_compression_ratio),
+            ..self
+        }
+    }
+
+    /// Check the entries we're going to extract, as described by the
+    /// central directory.
+    pub(crate) fn check_central_directory(
+        &self,
+        central_directory: &CentralDirectory,
+        selected: &[usize],
+    ) -> Result<()> {
+        self.check_entry_count(central_directory.entries.len() as u64)?;
+        let mut total = 0u64;
+        for &i in selected {
+            let entry = &central_directory.entries[i];
+            self.check_file(entry.compressed_size, entry.uncompressed_size)
+                .with_context(|| format!("Refusing to extract {}", entry.name))?;
+            total = total.saturating_add(entry.uncompressed_size);
+        }
+        match self.max_total_size {
+            Some(max) if total > max => {
+                fail!(
+                    Limit,
+                    "Files to extract total {total} bytes, more than the limit of {max} bytes"
+                )
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check how many entries we've found in the zip file so far.
+    pub(crate) fn check_entry_count(&self, entries: u64) -> Result<()> {
+        match self.max_entries {
+            Some(max) if entries > max => {
+                fail!(Limit, "Zip file has more than the limit of {max} entries")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the declared sizes of a single file.
+    pub(crate) fn check_file(&self, compressed_size: u64, uncompressed_size: u64) -> Result<()> {
+        if let Some(max) = self.max_file_size {
+            if uncompressed_size > max {
+                fail!(
+                    Limit,
+                    "File is {uncompressed_size} bytes, more than the limit of {max} bytes"
+                );
+            }
+        }
+        if let Some(max) = self.ratio_limit(compressed_size) {
+            if uncompressed_size > max {
+                fail!(
+                    Limit,
+                    "File would decompress to more than {} times its compressed size",
+                    self.max_compression_ratio.unwrap_or_default()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The largest a file can be given its compressed size. If that's not
+    /// known, for instance because it's in a data descriptor we haven't yet
+    /// reached, we can't enforce the compression ratio.
+    fn ratio_limit(&self, compressed_size: u64) -> Option<u64> {
+        match (self.max_compression_ratio, compressed_size) {
+            (Some(ratio), compressed_size) if compressed_size > 0 => {
+                Some(compressed_size.saturating_mul(ratio))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Enforces [`ExtractionLimits`] on the decompressed data of a file as it's
+/// read, failing once any limit is exceeded.
+pub(crate) struct LimitedReader<'a, R: Read + ?Sized> {
+    inner: &'a mut R,
+    limits: &'a ExtractionLimits,
+    compressed_size: u64,
+    read: u64,
+    /// How much has been read from all files so far.
+    total_read: &'a AtomicU64,
+}
+
+impl<'a, R: Read + ?Sized> LimitedReader<'a, R> {
+    pub(crate) fn new(
+        inner: &'a mut R,
+        limits: &'a ExtractionLimits,
+        compressed_size: u64,
+        total_read: &'a AtomicU64,
+    ) -> Self {
+        Self {
+            inner,
+            limits,
+            compressed_size,
+            read: 0,
+            total_read,
+        }
+    }
+}
+
+impl<R: Read + ?Sized> Read for LimitedReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        self.read += bytes_read as u64;
+        let total_read = self
+            .total_read
+            .fetch_add(bytes_read as u64, Ordering::Relaxed)
+            + bytes_read as u64;
+        let exceeded = |message: String| {
+            Err(std::io::Error::other(ClassifiedError::new(
+                FailureKind::Limit,
+                message,
+            )))
+        };
+        if let Some(max) = self.limits.max_file_size {
+            if self.read > max {
+                return exceeded(format!("File is larger than the limit of {max} bytes"));
+            }
+        }
+        if let Some(max) = self.limits.ratio_limit(self.compressed_size) {
+            if self.read > max {
+                return exceeded(format!(
+                    "File decompresses to more than {} times its compressed size",
+                    self.limits.max_compression_ratio.unwrap_or_default()
+                ));
+            }
+        }
+        if let Some(max) = self.limits.max_total_size {
+            if total_read > max {
+                return exceeded(format!(
+                    "Extracted files total more than the limit of {max} bytes"
+                ));
+            }
+        }
+        Ok(bytes_read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, sync::atomic::AtomicU64};
+    use test_log::test;
+
+    use super::{ExtractionLimits, LimitedReader};
+
+    #[test]
+    fn test_check_file() {
+        let limits = ExtractionLimits {
+            max_file_size: Some(1000),
+            max_compression_ratio: Some(10),
+            ..Default::default()
+        };
+        assert!(limits.check_file(100, 1000).is_ok());
+        assert!(limits.check_file(200, 1001).is_err());
+        assert!(limits.check_file(50, 501).is_err());
+        // The compressed size isn't known yet.
+        assert!(limits.check_file(0, 0).is_ok());
+    }
+
+    #[test]
+    fn test_in_memory_defaults() {
+        assert_eq!(
+            ExtractionLimits::default().or_in_memory_defaults(),
+            ExtractionLimits::IN_MEMORY
+        );
+        let limits = ExtractionLimits {
+            max_total_size: Some(u64::MAX),
+            max_entries: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(
+            limits.or_in_memory_defaults(),
+            ExtractionLimits {
+                max_compression_ratio: ExtractionLimits::IN_MEMORY.max_compression_ratio,
+                ..limits
+            }
+        );
+    }
+
+    #[test]
+    fn test_limited_reader() {
+        let limits = ExtractionLimits {
+            max_total_size: Some(150),
+            max_compression_ratio: Some(10),
+            ..Default::default()
+        };
+        let total_read = AtomicU64::new(0);
+        let mut data: &[u8] = &[0; 100];
+        let mut reader = LimitedReader::new(&mut data, &limits, 10, &total_read);
+        assert!(reader.read_to_end(&mut Vec::new()).is_ok());
+        // A file which lies about its compressed size.
+        let mut data: &[u8] = &[0; 100];
+        let mut reader = LimitedReader::new(&mut data, &limits, 5, &total_read);
+        let error = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert!(error.to_string().contains("10 times"));
+        // Too much in total.
+        let mut data: &[u8] = &[0; 100];
+        let mut reader = LimitedReader::new(&mut data, &limits, 0, &total_read);
+        let error = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert!(error.to_string().contains("total"));
+    }
+}
@@ -6,17 +6,39 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+#[cfg(feature = "async")]
+mod async_engine;
+mod cancellation;
+mod central_directory;
 mod cloneable_seekable_reader;
+mod consistency;
+mod error;
+mod filename_filter;
+mod free_space;
 mod http_range_reader;
+mod limits;
+mod overwrite_policy;
 mod progress_updater;
+mod range_planner;
+mod report;
 mod seekable_http_reader;
+mod sink;
+mod stream_reader;
+mod symlinks;
+mod timestamps;
+mod validation;
 
 use std::{
     borrow::Cow,
+    collections::{HashMap, HashSet},
     fs::File,
-    io::{ErrorKind, Read, Seek},
+    io::{Cursor, Read, Seek},
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
 };
 
 use anyhow::{Context, Result};
@@ -25,17 +47,100 @@ use zip::{read::ZipFile, ZipArchive};
 
 use crate::unzip::progress_updater::ProgressUpdater;
 
+#[cfg(feature = "async")]
+pub use self::async_engine::AsyncUnzipEngine;
+pub use self::cancellation::CancellationToken;
+pub use self::central_directory::ZipEntryInfo;
+pub use self::consistency::HeaderMismatch;
+pub use self::error::Error;
+pub use self::filename_filter::{FilenameFilter, FilenamePattern};
+pub use self::limits::ExtractionLimits;
+pub use self::overwrite_policy::OverwritePolicy;
+pub use self::report::{EntryFailure, EntryFailures, FailureKind, FailureMode};
+pub use self::sink::{
+    ExtractedFiles, ExtractionSink, FileMetadata, FilesystemSink, MemoryEntry, MemorySink,
+};
+pub use self::symlinks::SymlinkPolicy;
+pub use self::validation::ValidationPolicy;
+
 use self::{
-    cloneable_seekable_reader::HasLength,
+    cancellation::{CancelRegistration, CancellableReader},
+    central_directory::{enclosed_name, CentralDirectory},
+    cloneable_seekable_reader::{CloneableSeekableReader, HasLength, ReaderWithLength, SharedFile},
+    consistency::{check_local_header, check_name, check_trailer},
+    error::{fail, is_aborted, ClassifiedError},
+    limits::LimitedReader,
+    range_planner::{plan_local_header_ranges, plan_ranges},
     seekable_http_reader::{AccessPattern, SeekableHttpReader, SeekableHttpReaderEngine},
+    stream_reader::{EntryTrailer, LocalFileHeader, TeeReader, ZipStreamReader},
+    symlinks::{
+        is_symlink, path_from_bytes, read_symlink_target, target_is_contained, PendingSymlink,
+    },
+    timestamps::modification_time,
+    validation::{
+        check_duplicate_names, check_overlapping_entries, duplicate_name_problem, output_path_key,
+    },
 };
 
 /// Options for unzipping.
+#[derive(Default)]
 pub struct UnzipOptions {
-    /// The destination directory.
+    /// The destination directory, unless extracting into an
+    /// [`ExtractionSink`].
     pub output_directory: Option<PathBuf>,
     /// Whether to run in single-threaded mode.
     pub single_threaded: bool,
+    /// Which files within the zip file to extract. By default, all
+    /// files are extracted.
+    pub filename_filter: FilenameFilter,
+    /// What to do if a file we're extracting already exists. By default,
+    /// it is overwritten. Only applies to the output directory, not to an
+    /// [`ExtractionSink`].
+    pub overwrite_policy: OverwritePolicy,
+    /// What to do with symlinks which might point outside the output
+    /// directory. By default, they cause an error.
+    pub symlink_policy: SymlinkPolicy,
+    /// Whether to extract even if the central directory says the files
+    /// won't fit in the free space on the output filesystem. By default,
+    /// we refuse to start. When extracting while downloading, we can't see
+    /// the central directory until the end, so we check the length of the
+    /// download instead, if the server gave one and all files are selected.
+    pub skip_free_space_check: bool,
+    /// Limits on what we'll extract, to defend against zip bombs. By
+    /// default, there are none.
+    pub limits: ExtractionLimits,
+    /// What to do if the data of entries within the zip file overlaps.
+    /// By default, this is an error.
+    pub overlapping_entries: ValidationPolicy,
+    /// What to do if more than one entry would be extracted to the same
+    /// path. By default, this is an error: the first such entry is
+    /// extracted, and the others fail and are skipped.
+    pub duplicate_names: ValidationPolicy,
+    /// Whether names which differ only in case count as duplicates, as
+    /// they would be the same file on case-insensitive filesystems such as
+    /// the defaults on Windows and macOS. By default, they don't, since
+    /// such names are common and harmless on other filesystems.
+    pub case_insensitive_names: bool,
+    /// Whether to check that each file's local header agrees with its
+    /// central directory record before extracting it, failing with a
+    /// [`HeaderMismatch`] if not.
+    pub strict: bool,
+    /// Whether to stop at the first file which fails, or carry on and
+    /// report every failure. By default, we carry on.
+    pub failure_mode: FailureMode,
+    /// Cancels the extraction when triggered, for example from another
+    /// thread.
+    pub cancellation_token: CancellationToken,
+}
+
+impl UnzipOptions {
+    /// The sink for extracting into the output directory.
+    fn filesystem_sink(&self) -> FilesystemSink {
+        FilesystemSink::new(
+            self.output_directory.clone().unwrap_or_default(),
+            self.overwrite_policy,
+        )
+    }
 }
 
 /// A trait of types which wish to hear progress updates on the unzip.
@@ -51,11 +156,30 @@ pub trait UnzipProgressReporter: Sync {
     /// add up to the number you're given using `total_bytes_expected`.
     /// The 'count' parameter is _not_ a running total - you must add up
     /// each call to this function into the running total.
-    /// It's a bit unfortunate that we give compressed bytes rather than
-    /// uncompressed bytes, but currently we can't calculate uncompressed
-    /// bytes without downloading the whole zip file first, which rather
-    /// defeats the point.
+    /// This is in terms of compressed bytes, since that's what governs how
+    /// long a download takes. See `uncompressed_bytes_extracted` for how
+    /// much data has been written.
     fn bytes_extracted(&self, _count: u64) {}
+    /// The total number of uncompressed bytes we expect to write, according
+    /// to the central directory. This isn't reported when streaming a zip
+    /// file, since the central directory comes last.
+    fn total_uncompressed_bytes_expected(&self, _expected: u64) {}
+    /// Some uncompressed bytes have been written, or skipped because the
+    /// file already existed. This should eventually add up to the number
+    /// given to `total_uncompressed_bytes_expected`. As with
+    /// `bytes_extracted`, this is _not_ a running total.
+    fn uncompressed_bytes_extracted(&self, _count: u64) {}
+    /// We've started to download the whole zip file, which happens if the
+    /// server doesn't support ranges. Its length is given if known.
+    /// Files may be extracted while the download is in progress.
+    fn download_starting(&self, _length: Option<u64>) {}
+    /// Some bytes of the zip file have been downloaded. As with
+    /// `bytes_extracted`, this is _not_ a running total.
+    fn bytes_downloaded(&self, _count: u64) {}
+    /// We've stopped downloading the zip file. Usually that's because all
+    /// of it has been downloaded, but this is also reported if the download
+    /// fails or is cancelled, or if we stop early because a file failed.
+    fn download_finished(&self) {}
 }
 
 /// A progress reporter which does nothing.
@@ -69,96 +193,186 @@ impl UnzipProgressReporter for NullProgressReporter {}
 pub struct UnzipEngine<P: UnzipProgressReporter> {
     progress_reporter: P,
     options: UnzipOptions,
-    zipfile: Box<dyn UnzipEngineImpl>,
+    source: ZipSource,
     compressed_length: u64,
-    directory_creator: DirectoryCreator,
+}
+
+/// Where an [`UnzipEngine`] gets its zip file from.
+enum ZipSource {
+    /// A zip file whose central directory we've read, so we can extract
+    /// files in any order.
+    Seekable(Box<dyn UnzipEngineImpl>, CentralDirectory),
+    /// A download from an HTTP server which doesn't support ranges, which
+    /// we haven't yet started to read, and its length if known. Files are
+    /// extracted in the order in which they arrive, and checked against the
+    /// central directory once it arrives at the end.
+    Streaming(Box<dyn Read>, Option<u64>),
 }
 
 /// The underlying engine used by the unzipper. This is different
 /// for files and URIs.
 trait UnzipEngineImpl {
-    fn unzip(
-        &mut self,
-        single_threaded: bool,
-        output_directory: &Option<PathBuf>,
-        progress_reporter: &(dyn UnzipProgressReporter + Sync),
-        directory_creator: &DirectoryCreator,
-    ) -> Vec<anyhow::Error>;
+    /// Extract or test the files which `job` selects.
+    fn unzip(&mut self, job: &UnzipJob) -> Vec<EntryFailure>;
+
+    /// Read the local header of the file with the given index to find
+    /// where its data starts.
+    fn data_start(&mut self, index: usize) -> Result<u64>;
+
+    /// Prepare to call [`Self::data_start`] for every file, by avoiding
+    /// fetching anything but their local headers.
+    fn plan_local_headers(&mut self, _central_directory: &CentralDirectory) {}
+}
+
+/// Which files to unzip, and how: everything an [`UnzipEngineImpl`] needs
+/// besides the zip file itself.
+struct UnzipJob<'a> {
+    central_directory: &'a CentralDirectory,
+    /// Indices of the files to unzip.
+    selected: &'a [usize],
+    options: &'a UnzipOptions,
+    progress_reporter: &'a (dyn UnzipProgressReporter + Sync),
+    operation: Operation<'a>,
+}
+
+/// What to do with each file we're asked to unzip.
+#[derive(Clone, Copy)]
+enum Operation<'a> {
+    /// Write it to the sink of this extraction.
+    Extract(&'a ExtractionState<'a>),
+    /// Decompress it and check its CRC, but discard the data.
+    Test,
 }
 
-/// Engine which knows how to unzip a file.
+/// Engine which knows how to unzip a file, or any other cheaply cloneable
+/// seekable reader.
 #[derive(Clone)]
-struct UnzipFileEngine<'a>(ZipArchive<positioned_io::SizeCursor<&'a File>>);
-
-impl<'a> UnzipEngineImpl for UnzipFileEngine<'a> {
-    fn unzip(
-        &mut self,
-        single_threaded: bool,
-        output_directory: &Option<PathBuf>,
-        progress_reporter: &(dyn UnzipProgressReporter + Sync),
-        directory_creator: &DirectoryCreator,
-    ) -> Vec<anyhow::Error> {
-        unzip_serial_or_parallel(
-            self.0.len(),
-            single_threaded,
-            output_directory,
-            progress_reporter,
-            directory_creator,
-            || self.0.clone(),
-        )
+struct UnzipFileEngine<R: Read + Seek + Clone + Sync>(ZipArchive<R>);
+
+impl<R: Read + Seek + Clone + Sync> UnzipEngineImpl for UnzipFileEngine<R> {
+    fn unzip(&mut self, job: &UnzipJob) -> Vec<EntryFailure> {
+        unzip_serial_or_parallel(job, || self.0.clone(), || {})
+    }
+
+    fn data_start(&mut self, index: usize) -> Result<u64> {
+        Ok(self.0.by_index_raw(index)?.data_start())
     }
 }
 
 /// Engine which knows how to unzip a URI; specifically a URI fetched from
 /// an HTTP server which supports `Range` requests.
-#[derive(Clone)]
-struct UnzipUriEngine<F: Fn()>(
-    Arc<SeekableHttpReaderEngine>,
-    ZipArchive<SeekableHttpReader>,
-    F,
-);
+struct UnzipUriEngine<F: Fn()> {
+    reader_engine: Arc<SeekableHttpReaderEngine>,
+    archive: ZipArchive<SeekableHttpReader>,
+    callback_on_rewind: F,
+    /// Wakes any readers when cancelled, for as long as we exist.
+    _cancel_registration: CancelRegistration,
+}
 
 impl<F: Fn()> UnzipEngineImpl for UnzipUriEngine<F> {
-    fn unzip(
-        &mut self,
-        single_threaded: bool,
-        output_directory: &Option<PathBuf>,
-        progress_reporter: &(dyn UnzipProgressReporter + Sync),
-        directory_creator: &DirectoryCreator,
-    ) -> Vec<anyhow::Error> {
-        self.0
+    fn unzip(&mut self, job: &UnzipJob) -> Vec<EntryFailure> {
+        let planned_ranges = if job.selected.len() < job.central_directory.entries.len() {
+            // We only want some of the files, so only fetch the parts of
+            // the zip file which contain them.
+            plan_ranges(job.central_directory, job.selected)
+        } else {
+            Vec::new()
+        };
+        self.reader_engine.set_planned_ranges(planned_ranges);
+        self.reader_engine
             .set_expected_access_pattern(AccessPattern::SequentialIsh);
         let result = unzip_serial_or_parallel(
-            self.1.len(),
-            single_threaded,
-            output_directory,
-            progress_reporter,
-            directory_creator,
-            || self.1.clone(),
+            job,
+            || self.archive.clone(),
+            // Stop downloading the rest of the zip file.
+            || self.reader_engine.abort(),
         );
-        let stats = self.0.get_stats();
+        let stats = self.reader_engine.get_stats();
         if stats.cache_shrinks > 0 {
-            self.2()
+            (self.callback_on_rewind)()
         }
         result
     }
+
+    fn data_start(&mut self, index: usize) -> Result<u64> {
+        Ok(self.archive.by_index_raw(index)?.data_start())
+    }
+
+    fn plan_local_headers(&mut self, central_directory: &CentralDirectory) {
+        // Otherwise, we'd download and cache everything between the
+        // headers.
+        self.reader_engine
+            .set_planned_ranges(plan_local_header_ranges(central_directory));
+    }
 }
 
 impl<P: UnzipProgressReporter> UnzipEngine<P> {
     /// Create an unzip engine which knows how to unzip a file.
-    pub fn for_file(zipfile: File, options: UnzipOptions, progress_reporter: P) -> Result<Self> {
+    pub fn for_file(
+        zipfile: File,
+        options: UnzipOptions,
+        progress_reporter: P,
+    ) -> Result<Self, Error> {
         // The following line doesn't actually seem to make any significant
         // performance difference.
         // let zipfile = BufReader::new(zipfile);
         let compressed_length = zipfile.len();
-        let zipfile = Box::leak(Box::new(zipfile));
-        let zipfile = positioned_io::SizeCursor::new(&*zipfile);
+        let zipfile = positioned_io::SizeCursor::new(SharedFile::new(zipfile));
+        Self::for_cloneable_reader(zipfile, compressed_length, options, progress_reporter)
+    }
+
+    /// Create an unzip engine for a reader which is cheap to clone, and
+    /// whose clones can be read independently, in parallel.
+    pub(crate) fn for_cloneable_reader<R: Read + Seek + Clone + Sync + 'static>(
+        reader: R,
+        compressed_length: u64,
+        options: UnzipOptions,
+        progress_reporter: P,
+    ) -> Result<Self, Error> {
+        let archive = ZipArchive::new(reader.clone())?;
+        let central_directory = read_central_directory(reader, &archive)?;
+        Ok(Self {
+            progress_reporter,
+            options,
+            source: ZipSource::Seekable(Box::new(UnzipFileEngine(archive)), central_directory),
+            compressed_length,
+        })
+    }
+
+    /// Create an unzip engine for a zip file which can only be read from
+    /// start to finish, such as a download from a server which doesn't
+    /// support ranges.
+    pub(crate) fn for_download(
+        download: Box<dyn Read>,
+        length: Option<u64>,
+        options: UnzipOptions,
+        progress_reporter: P,
+    ) -> Self {
+        Self {
+            progress_reporter,
+            options,
+            source: ZipSource::Streaming(download, length),
+            compressed_length: length.unwrap_or_default(),
+        }
+    }
+
+    /// Create an unzip engine which knows how to unzip a zip file from any
+    /// seekable reader, such as a [`std::io::Cursor`] over data in memory.
+    /// Access to the reader is serialized, but decompression can still
+    /// happen in parallel.
+    pub fn for_reader<R: Read + Seek + Send + 'static>(
+        reader: R,
+        options: UnzipOptions,
+        progress_reporter: P,
+    ) -> Result<Self, Error> {
+        let reader = ReaderWithLength::new(reader)?;
+        let compressed_length = reader.len();
+        let (zipfile, central_directory) = open_seekable(reader)?;
         Ok(Self {
             progress_reporter,
             options,
-            zipfile: Box::new(UnzipFileEngine(ZipArchive::new(zipfile)?)),
+            source: ZipSource::Seekable(zipfile, central_directory),
             compressed_length,
-            directory_creator: DirectoryCreator::default(),
         })
     }
 
@@ -177,44 +391,55 @@ impl<P: UnzipProgressReporter> UnzipEngine<P> {
         readahead_limit: Option<usize>,
         progress_reporter: P,
         callback_on_rewind: F,
-    ) -> Result<Self> {
+    ) -> Result<Self, Error> {
         let seekable_http_reader = SeekableHttpReaderEngine::new(
             uri.to_string(),
             readahead_limit,
             AccessPattern::RandomAccess,
         );
-        let (compressed_length, zipfile): (u64, Box<dyn UnzipEngineImpl>) =
-            match seekable_http_reader {
-                Ok(seekable_http_reader) => (
-                    seekable_http_reader.len(),
-                    Box::new(UnzipUriEngine(
-                        seekable_http_reader.clone(),
-                        ZipArchive::new(seekable_http_reader.create_reader())?,
-                        callback_on_rewind,
-                    )),
-                ),
-                Err(_) => {
-                    // This server probably doesn't support HTTP ranges.
-                    // Let's fall back to fetching the request into a temporary
-                    // file then unzipping.
-                    let mut response = reqwest::blocking::get(uri)?;
-                    let mut tempfile = Box::leak(Box::new(tempfile::tempfile()?));
-                    std::io::copy(&mut response, &mut tempfile)?;
-                    let compressed_length = tempfile.len();
-                    let zipfile = positioned_io::SizeCursor::new(&*tempfile);
-                    (
-                        compressed_length,
-                        Box::new(UnzipFileEngine(ZipArchive::new(zipfile).unwrap())),
-                    )
-                }
-            };
-        Ok(Self {
-            progress_reporter,
-            options,
-            zipfile,
-            compressed_length,
-            directory_creator: DirectoryCreator::default(),
-        })
+        match seekable_http_reader {
+            Ok(seekable_http_reader) => {
+                // Cancelling should wake any threads waiting for data.
+                let engine = Arc::downgrade(&seekable_http_reader);
+                let cancel_registration = options.cancellation_token.on_cancel(move || {
+                    if let Some(engine) = engine.upgrade() {
+                        engine.abort();
+                    }
+                });
+                let archive = ZipArchive::new(seekable_http_reader.clone().create_reader())?;
+                // We're still in random access mode, so this will be
+                // serviced from data already read by `zip-rs` where possible.
+                let central_directory =
+                    read_central_directory(seekable_http_reader.clone().create_reader(), &archive)?;
+                Ok(Self {
+                    progress_reporter,
+                    options,
+                    compressed_length: seekable_http_reader.len(),
+                    source: ZipSource::Seekable(
+                        Box::new(UnzipUriEngine {
+                            reader_engine: seekable_http_reader,
+                            archive,
+                            callback_on_rewind,
+                            _cancel_registration: cancel_registration,
+                        }),
+                        central_directory,
+                    ),
+                })
+            }
+            Err(_) => {
+                // This server probably doesn't support HTTP ranges.
+                // Let's fall back to extracting files as the zip file
+                // downloads, which we'll only start when asked to unzip.
+                let response = reqwest::blocking::get(uri)?.error_for_status()?;
+                let length = response.content_length();
+                Ok(Self::for_download(
+                    Box::new(response),
+                    length,
+                    options,
+                    progress_reporter,
+                ))
+            }
+        }
     }
 
     /// The total compressed length that we expect to retrieve over
@@ -223,47 +448,747 @@ impl<P: UnzipProgressReporter> UnzipEngine<P> {
         self.compressed_length
     }
 
-    /// Perform the unzip.
-    pub fn unzip(mut self) -> Result<()> {
+    /// Information about every file within the zip file, in central
+    /// directory order. For a URI, only the central directory needs to be
+    /// fetched, unless the server doesn't support ranges, in which case
+    /// the whole zip file is downloaded.
+    pub fn entries(&mut self) -> Result<Vec<ZipEntryInfo>, Error> {
+        let (_, central_directory) = self.seekable()?;
+        Ok(central_directory
+            .entries
+            .iter()
+            .map(ZipEntryInfo::from)
+            .collect())
+    }
+
+    /// Like [`UnzipEngine::entries`], but also reads the local header of
+    /// each file to find where its data starts. For a URI, this involves
+    /// fetching a small part of the zip file for every entry.
+    pub fn entries_with_data_start(&mut self) -> Result<Vec<ZipEntryInfo>, Error> {
+        let (zipfile, central_directory) = self.seekable()?;
+        zipfile.plan_local_headers(central_directory);
+        central_directory
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| {
+                let mut info = ZipEntryInfo::from(entry);
+                info.data_start =
+                    Some(zipfile.data_start(i).with_context(|| {
+                        format!("Failed to read local header of {}", entry.name)
+                    })?);
+                Ok(info)
+            })
+            .collect()
+    }
+
+    /// Get random access to the zip file, first downloading all of it if
+    /// we'd planned to stream it.
+    fn seekable(&mut self) -> Result<(&mut dyn UnzipEngineImpl, &CentralDirectory)> {
+        if let ZipSource::Streaming(response, length) = &mut self.source {
+            let mut tempfile = tempfile::tempfile()?;
+            let progress_reporter = &self.progress_reporter;
+            let download = Download::start(progress_reporter, *length);
+            let response = progress_streams::ProgressReader::new(response, |bytes_read| {
+                progress_reporter.bytes_downloaded(bytes_read as u64)
+            });
+            let mut response =
+                CancellableReader::new(response, self.options.cancellation_token.clone());
+            std::io::copy(&mut response, &mut tempfile)
+                .with_context(|| "Failed to download zip file")?;
+            drop(download);
+            self.compressed_length = tempfile.len();
+            let (zipfile, central_directory) = open_seekable(tempfile)?;
+            self.source = ZipSource::Seekable(zipfile, central_directory);
+        }
+        match &mut self.source {
+            ZipSource::Seekable(zipfile, central_directory) => {
+                Ok((zipfile.as_mut(), central_directory))
+            }
+            ZipSource::Streaming(..) => unreachable!(),
+        }
+    }
+
+    /// Perform the unzip. If any files can't be extracted, the others are
+    /// still attempted (unless failing fast), and the error is
+    /// [`Error::Entries`] listing every file which failed.
+    pub fn unzip(self) -> Result<(), Error> {
+        let sink = self.options.filesystem_sink();
+        self.unzip_into(&sink)
+    }
+
+    /// Perform the unzip, as [`UnzipEngine::unzip`], but into memory rather
+    /// than the output directory. Files are still extracted in parallel.
+    /// Since everything is held in memory at once, a zip bomb must not be
+    /// able to exhaust it: any total size or compression ratio not set in
+    /// [`UnzipOptions::limits`] is taken from [`ExtractionLimits::IN_MEMORY`].
+    /// Set them explicitly to allow more, or use [`UnzipEngine::unzip_into`]
+    /// with a [`MemorySink`] to apply no limits at all.
+    pub fn extract_to_memory(mut self) -> Result<ExtractedFiles, Error> {
+        self.options.limits = self.options.limits.or_in_memory_defaults();
+        let sink = MemorySink::new();
+        self.unzip_into(&sink)?;
+        Ok(sink.into())
+    }
+
+    /// Perform the unzip, as [`UnzipEngine::unzip`], but into `sink`
+    /// rather than the output directory.
+    pub fn unzip_into(self, sink: &dyn ExtractionSink) -> Result<(), Error> {
         log::info!("Starting extract");
+        let state = ExtractionState::new(sink);
+        let mut errors = match self.source {
+            ZipSource::Seekable(mut zipfile, central_directory) => {
+                let mut selected = selected_indices(&central_directory, &self.options);
+                let duplicates = check_duplicate_names(
+                    &central_directory,
+                    &mut selected,
+                    self.options.duplicate_names,
+                    self.options.case_insensitive_names,
+                );
+                if self.options.failure_mode.should_stop(&duplicates) {
+                    return Ok(EntryFailures::into_result(duplicates)?);
+                }
+                self.progress_reporter
+                    .total_bytes_expected(total_bytes_expected(
+                        &central_directory,
+                        &selected,
+                        self.compressed_length,
+                    ));
+                self.options
+                    .limits
+                    .check_central_directory(&central_directory, &selected)?;
+                check_overlapping_entries(&central_directory, self.options.overlapping_entries)?;
+                let total_uncompressed_bytes_expected =
+                    total_uncompressed_bytes_expected(&central_directory, &selected);
+                if !self.options.skip_free_space_check {
+                    sink.check_free_space(total_uncompressed_bytes_expected)?;
+                }
+                self.progress_reporter
+                    .total_uncompressed_bytes_expected(total_uncompressed_bytes_expected);
+                let mut errors = duplicates;
+                errors.extend(zipfile.unzip(&UnzipJob {
+                    central_directory: &central_directory,
+                    selected: &selected,
+                    options: &self.options,
+                    progress_reporter: &self.progress_reporter,
+                    operation: Operation::Extract(&state),
+                }));
+                errors.sort_by_key(|failure| failure.index);
+                errors
+            }
+            ZipSource::Streaming(response, length) => {
+                // The files will usually extract to more than the zip file,
+                // so its length is a rough lower bound on the space needed.
+                let all_selected = self.options.filename_filter.include.is_empty()
+                    && self.options.filename_filter.exclude.is_empty();
+                if let Some(length) = length.filter(|_| all_selected) {
+                    if !self.options.skip_free_space_check {
+                        sink.check_free_space(length)?;
+                    }
+                }
+                self.progress_reporter
+                    .total_bytes_expected(self.compressed_length);
+                unzip_while_downloading(
+                    response,
+                    length,
+                    &self.options,
+                    &self.progress_reporter,
+                    &state,
+                )?
+            }
+        };
+        self.options.cancellation_token.check()?;
+        // Only now that all regular files have been written can we safely
+        // create symlinks, and then set the modification times of
+        // directories.
+        if !self.options.failure_mode.should_stop(&errors) {
+            errors.extend(state.create_symlinks());
+            errors.extend(state.set_directory_metadata());
+        }
+        Ok(EntryFailures::into_result(errors)?)
+    }
+
+    /// Check the integrity of the files we'd extract, by decompressing them
+    /// and checking their CRCs, without writing anything. If any are
+    /// corrupt, the error is [`Error::Entries`] listing all of them. For a
+    /// URI on a server which doesn't support ranges, the whole zip file is
+    /// downloaded first.
+    pub fn test(mut self) -> Result<(), Error> {
+        log::info!("Starting test");
+        self.seekable()?;
+        let ZipSource::Seekable(zipfile, central_directory) = &mut self.source else {
+            unreachable!()
+        };
+        let selected = selected_indices(central_directory, &self.options);
         self.progress_reporter
-            .total_bytes_expected(self.compressed_length);
-        let output_directory = &self.options.output_directory;
-        let single_threaded = self.options.single_threaded;
-        let errors = self.zipfile.unzip(
-            single_threaded,
-            output_directory,
-            &self.progress_reporter,
-            &self.directory_creator,
+            .total_bytes_expected(total_bytes_expected(
+                central_directory,
+                &selected,
+                self.compressed_length,
+            ));
+        self.progress_reporter.total_uncompressed_bytes_expected(
+            total_uncompressed_bytes_expected(central_directory, &selected),
         );
-        // Return the first error code, if any.
-        errors.into_iter().next().map(Result::Err).unwrap_or(Ok(()))
+        let errors = zipfile.unzip(&UnzipJob {
+            central_directory,
+            selected: &selected,
+            options: &self.options,
+            progress_reporter: &self.progress_reporter,
+            operation: Operation::Test,
+        });
+        self.options.cancellation_token.check()?;
+        Ok(EntryFailures::into_result(errors)?)
+    }
+}
+
+/// The number of compressed bytes we expect to report progress for when
+/// unzipping the given files.
+fn total_bytes_expected(
+    central_directory: &CentralDirectory,
+    selected: &[usize],
+    compressed_length: u64,
+) -> u64 {
+    if selected.len() < central_directory.entries.len() {
+        // Progress is reported per file in terms of compressed bytes, so
+        // we'll only ever report the sum of the files we extract.
+        selected
+            .iter()
+            .map(|&i| central_directory.entries[i].compressed_size)
+            .fold(0u64, u64::saturating_add)
+    } else {
+        compressed_length
+    }
+}
+
+/// The number of uncompressed bytes in the given files.
+fn total_uncompressed_bytes_expected(
+    central_directory: &CentralDirectory,
+    selected: &[usize],
+) -> u64 {
+    selected
+        .iter()
+        .map(|&i| central_directory.entries[i].uncompressed_size)
+        .fold(0u64, u64::saturating_add)
+}
+
+/// The indices of the files which our filename filter allows us to
+/// extract. Files whose names aren't safe are included, so that we
+/// report an error when we come to extract them.
+fn selected_indices(central_directory: &CentralDirectory, options: &UnzipOptions) -> Vec<usize> {
+    central_directory
+        .entries
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| {
+            entry
+                .enclosed_name()
+                .map(|name| options.filename_filter.should_extract(name))
+                .unwrap_or(true)
+        })
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// An object which can unzip a zip file from a stream which can't seek,
+/// such as standard input. Each file is extracted as soon as it arrives,
+/// using only the information in its local file header. Since the central
+/// directory at the end of the zip file is never consulted, Unix
+/// permissions aren't restored and symlinks are extracted as regular files.
+pub struct StreamingUnzipEngine<R: Read, P: UnzipProgressReporter> {
+    stream: ZipStreamReader<R>,
+    options: UnzipOptions,
+    progress_reporter: P,
+    length: Option<u64>,
+}
+
+impl<R: Read, P: UnzipProgressReporter> StreamingUnzipEngine<R, P> {
+    /// Create an unzip engine which reads a zip file from a stream.
+    pub fn new(reader: R, options: UnzipOptions, progress_reporter: P) -> Self {
+        Self {
+            stream: ZipStreamReader::new(reader),
+            options,
+            progress_reporter,
+            length: None,
+        }
+    }
+
+    /// Tell the engine how long the stream is, if known, so that it can be
+    /// passed to [`UnzipProgressReporter::total_bytes_expected`].
+    pub fn with_length(mut self, length: u64) -> Self {
+        self.length = Some(length);
+        self
+    }
+
+    /// Perform the unzip. Files are extracted one at a time, in the order
+    /// in which they appear in the stream. If any can't be extracted, the
+    /// error is [`Error::Entries`] listing all of them.
+    pub fn unzip(self) -> Result<(), Error> {
+        let sink = self.options.filesystem_sink();
+        self.unzip_into(&sink)
+    }
+
+    /// Perform the unzip, as [`StreamingUnzipEngine::unzip`], but into
+    /// `sink` rather than the output directory.
+    pub fn unzip_into(mut self, sink: &dyn ExtractionSink) -> Result<(), Error> {
+        log::info!("Starting streaming extract");
+        if let Some(length) = self.length {
+            self.progress_reporter.total_bytes_expected(length);
+        }
+        let state = ExtractionState::new(sink);
+        let mut errors = Vec::new();
+        extract_from_stream(
+            &mut self.stream,
+            &self.options,
+            &self.progress_reporter,
+            &state,
+            &mut errors,
+            |_| {},
+        )?;
+        self.options.cancellation_token.check()?;
+        if !self.options.failure_mode.should_stop(&errors) {
+            errors.extend(state.create_symlinks());
+            errors.extend(state.set_directory_metadata());
+        }
+        Ok(EntryFailures::into_result(errors)?)
+    }
+}
+
+/// A file which we came across while streaming a zip file, and which we
+/// tried to extract, or refused to because of its name.
+struct StreamedEntry {
+    header: LocalFileHeader,
+    /// Whether we reached the end of the file's data. If not, the stream
+    /// failed partway through it.
+    finished: bool,
+    /// The CRC and sizes of the file, if it was extracted successfully.
+    trailer: Option<EntryTrailer>,
+    /// Whether we wrote the file, and left it in place, rather than
+    /// skipping it or abandoning it.
+    written: bool,
+}
+
+impl StreamedEntry {
+    /// Remove the file we wrote, if any, since it turned out not to be the
+    /// file which the central directory describes.
+    fn discard(&self, state: &ExtractionState) -> Result<()> {
+        if !self.written || self.header.name.ends_with('/') {
+            return Ok(());
+        }
+        let Some(name) = enclosed_name(&self.header.name) else {
+            return Ok(());
+        };
+        state
+            .sink
+            .remove_file(name)
+            .with_context(|| format!("Failed to remove {}", name.display()))
+    }
+
+    /// Remove the file we wrote, if any, because it was corrupt or we
+    /// couldn't check it. We've already failed, so any error is only
+    /// logged.
+    fn abandon(&mut self, state: &ExtractionState) {
+        if let Err(e) = self.discard(state) {
+            log::warn!("{e:#}");
+        }
+        self.written = false;
+    }
+}
+
+/// Extracts files from a stream, in the order in which they arrive, until
+/// we reach the central directory. Errors extracting individual files are
+/// added to `errors`, and every file we try to extract, or refuse to as a
+/// duplicate, is passed to `record`. An error in the stream itself means we
+/// can't continue, so is returned.
+fn extract_from_stream<R: Read>(
+    stream: &mut ZipStreamReader<R>,
+    options: &UnzipOptions,
+    progress_reporter: &dyn UnzipProgressReporter,
+    state: &ExtractionState,
+    errors: &mut Vec<EntryFailure>,
+    mut record: impl FnMut(StreamedEntry),
+) -> Result<()> {
+    let mut entry_count = 0;
+    // Files we've extracted so far, to detect duplicates.
+    let mut extracted = HashSet::new();
+    while let Some(header) = stream.next_header()? {
+        options.cancellation_token.check()?;
+        if options.failure_mode.should_stop(errors) {
+            break;
+        }
+        entry_count += 1;
+        options.limits.check_entry_count(entry_count)?;
+        let entry = EntryMetadata::from(&header);
+        let selected = enclosed_name(&header.name)
+            .map(|name| options.filename_filter.should_extract(name))
+            .unwrap_or(true);
+        let refused = match enclosed_name(&header.name) {
+            Some(name)
+                if selected
+                    && !entry.is_dir()
+                    && !extracted.insert(output_path_key(name, options.case_insensitive_names)) =>
+            {
+                options
+                    .duplicate_names
+                    .apply(|| duplicate_name_problem(name))
+                    .err()
+            }
+            _ => None,
+        };
+        let duplicate = refused.is_some();
+        if let Some(e) = refused {
+            errors.push(EntryFailure::new(&header.name, None, e));
+        }
+        if !selected || duplicate {
+            stream.skip_entry(&header)?;
+            // Progress is measured through the whole stream, so count the
+            // files we skip too.
+            progress_reporter.bytes_extracted(stream.position() - header.data_start);
+            if duplicate {
+                // Record it so that it isn't extracted later from the
+                // central directory.
+                record(StreamedEntry {
+                    header,
+                    finished: true,
+                    trailer: None,
+                    written: false,
+                });
+            }
+            continue;
+        }
+        let mut started = false;
+        let mut written = false;
+        let result = stream.read_entry(&header, |reader| {
+            started = true;
+            written = extract_file_inner(&entry, reader, options, progress_reporter, state)?
+                == ExtractOutcome::Written;
+            Ok(())
+        });
+        let result = match result {
+            Ok(result) => result,
+            Err(e) => {
+                if started {
+                    let mut streamed = StreamedEntry {
+                        header,
+                        finished: false,
+                        trailer: None,
+                        written,
+                    };
+                    // We can't check what we wrote, for example because
+                    // we were cancelled.
+                    streamed.abandon(state);
+                    record(streamed);
+                }
+                return Err(e);
+            }
+        };
+        let trailer = match result {
+            Ok(trailer) => Some(trailer),
+            Err(e) => {
+                let e = e.context(format!("Failed to extract {}", entry.display_name()));
+                errors.push(EntryFailure::new(&header.name, None, e));
+                None
+            }
+        };
+        let mut streamed = StreamedEntry {
+            header,
+            finished: true,
+            trailer,
+            written,
+        };
+        if streamed.trailer.is_none() {
+            // The data was corrupt.
+            streamed.abandon(state);
+        }
+        record(streamed);
+    }
+    Ok(())
+}
+
+/// A download of a whole zip file, which tells the progress reporter that
+/// it's finished when dropped, however we stop downloading.
+struct Download<'a> {
+    progress_reporter: &'a dyn UnzipProgressReporter,
+}
+
+impl<'a> Download<'a> {
+    fn start(progress_reporter: &'a dyn UnzipProgressReporter, length: Option<u64>) -> Self {
+        progress_reporter.download_starting(length);
+        Self { progress_reporter }
+    }
+}
+
+impl Drop for Download<'_> {
+    fn drop(&mut self) {
+        self.progress_reporter.download_finished();
     }
 }
 
-fn unzip_serial_or_parallel<'a, T: Read + Seek + 'a>(
-    len: usize,
-    single_threaded: bool,
-    output_directory: &Option<PathBuf>,
+/// Extracts files from a zip file as it downloads, using only their local
+/// file headers, while keeping a copy of the download in a temporary file.
+/// Once it's complete, we read the central directory from that copy to check
+/// the files we extracted, restore what the local headers couldn't tell us
+/// (permissions and symlinks), and extract any files we missed.
+fn unzip_while_downloading(
+    response: impl Read,
+    length: Option<u64>,
+    options: &UnzipOptions,
     progress_reporter: &dyn UnzipProgressReporter,
-    directory_creator: &DirectoryCreator,
+    state: &ExtractionState,
+) -> Result<Vec<EntryFailure>> {
+    let tempfile = tempfile::tempfile()?;
+    let download = Download::start(progress_reporter, length);
+    let response = progress_streams::ProgressReader::new(response, |bytes_read| {
+        progress_reporter.bytes_downloaded(bytes_read as u64)
+    });
+    let response = CancellableReader::new(response, options.cancellation_token.clone());
+    let mut stream = ZipStreamReader::new(TeeReader::new(response, &tempfile));
+    let mut streamed = Vec::new();
+    let mut errors = Vec::new();
+    if let Err(e) = extract_from_stream(
+        &mut stream,
+        options,
+        progress_reporter,
+        state,
+        &mut errors,
+        |entry| streamed.push(entry),
+    ) {
+        if options.cancellation_token.is_cancelled()
+            || streamed.last().is_some_and(|entry| !entry.finished)
+        {
+            // We may have written part of a file, so we can't just extract
+            // it again later.
+            return Err(e);
+        }
+        // For example, the stream contains a file whose end can't be found
+        // without the central directory.
+        log::warn!("Falling back to extracting once download is complete: {e:#}");
+    }
+    if options.failure_mode.should_stop(&errors) {
+        // Don't download the rest of the zip file.
+        return Ok(errors);
+    }
+    stream
+        .skip_to_end()
+        .with_context(|| "Failed to download zip file")?;
+    drop(stream);
+    drop(download);
+    let (mut zipfile, central_directory) = open_seekable(tempfile)?;
+    let by_header_start: HashMap<u64, usize> = central_directory
+        .entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| (entry.header_start, i))
+        .collect();
+    let mut seen = vec![false; central_directory.entries.len()];
+    let mut symlinks = Vec::new();
+    for streamed_entry in streamed {
+        let header = &streamed_entry.header;
+        let Some(&i) = by_header_start.get(&header.header_start) else {
+            let e = ClassifiedError::new(
+                FailureKind::Archive,
+                format!(
+                    "Extracted {} but it isn't in the central directory",
+                    header.name
+                ),
+            );
+            errors.push(EntryFailure::new(&header.name, None, e.into()));
+            if let Err(e) = streamed_entry.discard(state) {
+                errors.push(EntryFailure::new(&header.name, None, e));
+            }
+            continue;
+        };
+        seen[i] = true;
+        let entry = &central_directory.entries[i];
+        let mismatch = check_name(entry, header).and_then(|()| {
+            streamed_entry
+                .trailer
+                .as_ref()
+                .map_or(Ok(()), |trailer| check_trailer(entry, trailer))
+        });
+        if let Err(mismatch) = mismatch {
+            // What we extracted came from the local header, so it isn't
+            // necessarily the file which the central directory describes.
+            errors.push(EntryFailure::new(&entry.name, Some(i), mismatch.into()));
+            if let Err(e) = streamed_entry.discard(state) {
+                errors.push(EntryFailure::new(&entry.name, Some(i), e));
+            }
+            continue;
+        }
+        if !streamed_entry.written {
+            continue;
+        }
+        if header.name != entry.name {
+            // Only the UTF-8 flag differs, so we decoded the name
+            // differently and wrote the file to the wrong path. Extract it
+            // again from the central directory, along with any we missed.
+            match streamed_entry.discard(state) {
+                Ok(()) => seen[i] = false,
+                Err(e) => errors.push(EntryFailure::new(&entry.name, Some(i), e)),
+            }
+            continue;
+        }
+        let name = entry
+            .enclosed_name()
+            .expect("extracted files have safe names");
+        let unix_mode = entry.unix_mode();
+        if is_symlink(unix_mode) && cfg!(unix) {
+            // We wrote the symlink's target into a regular file. Replace it
+            // with the real thing.
+            if let Err(e) = state.sink.remove_file(name).with_context(|| {
+                format!(
+                    "Failed to remove {} to replace it with a symlink",
+                    name.display()
+                )
+            }) {
+                errors.push(EntryFailure::new(&entry.name, Some(i), e));
+                continue;
+            }
+            symlinks.push(i);
+            continue;
+        }
+        if unix_mode.is_some() {
+            let metadata = FileMetadata {
+                unix_mode,
+                mtime: None,
+            };
+            if let Err(e) = state
+                .sink
+                .set_metadata(name, &metadata)
+                .with_context(|| format!("Failed to set permissions of {}", name.display()))
+            {
+                errors.push(EntryFailure::new(&entry.name, Some(i), e));
+            }
+        }
+    }
+    if options.failure_mode.should_stop(&errors) {
+        return Ok(errors);
+    }
+    // We've already reported the progress of these.
+    errors.extend(zipfile.unzip(&UnzipJob {
+        central_directory: &central_directory,
+        selected: &symlinks,
+        options,
+        progress_reporter: &NullProgressReporter,
+        operation: Operation::Extract(state),
+    }));
+    let missed: Vec<_> = selected_indices(&central_directory, options)
+        .into_iter()
+        .filter(|&i| !seen[i])
+        .collect();
+    if !missed.is_empty() && !options.failure_mode.should_stop(&errors) {
+        // The stream can't contain overlapping entries, but the central
+        // directory might point at them. If it does, the files we missed
+        // fail, along with any which already have.
+        if let Err(e) = check_overlapping_entries(&central_directory, options.overlapping_entries) {
+            let problem = format!("{e:#}");
+            errors.extend(missed.iter().map(|&i| {
+                let e = ClassifiedError::new(FailureKind::Security, problem.clone());
+                EntryFailure::new(&central_directory.entries[i].name, Some(i), e.into())
+            }));
+            return Ok(errors);
+        }
+        log::warn!(
+            "Extracting {} files which we couldn't extract as they downloaded",
+            missed.len()
+        );
+        errors.extend(zipfile.unzip(&UnzipJob {
+            central_directory: &central_directory,
+            selected: &missed,
+            options,
+            progress_reporter,
+            operation: Operation::Extract(state),
+        }));
+    }
+    Ok(errors)
+}
+
+/// Create an engine which extracts files from a seekable reader, and read
+/// its central directory.
+fn open_seekable<R: Read + Seek + HasLength + Send + 'static>(
+    reader: R,
+) -> Result<(Box<dyn UnzipEngineImpl>, CentralDirectory)> {
+    let reader = CloneableSeekableReader::new(reader);
+    let archive = ZipArchive::new(reader.clone())?;
+    let central_directory = read_central_directory(reader, &archive)?;
+    Ok((Box::new(UnzipFileEngine(archive)), central_directory))
+}
+
+/// Read our own copy of the central directory, which must agree with
+/// the one `zip-rs` has already read.
+fn read_central_directory<T: Read + Seek>(
+    reader: impl Read + Seek,
+    archive: &ZipArchive<T>,
+) -> Result<CentralDirectory> {
+    let central_directory = CentralDirectory::read(reader)?;
+    if central_directory.entries.len() != archive.len() {
+        fail!(
+            Archive,
+            "Central directory has {} entries but zip-rs found {}",
+            central_directory.entries.len(),
+            archive.len()
+        );
+    }
+    Ok(central_directory)
+}
+
+fn unzip_serial_or_parallel<'a, T: Read + Seek + Clone + 'a>(
+    job: &UnzipJob,
     get_ziparchive_clone: impl Fn() -> ZipArchive<T> + Sync,
-) -> Vec<anyhow::Error> {
-    if single_threaded {
-        (0..len)
-            .map(|i| {
-                extract_file(
+    abort: impl Fn() + Sync,
+) -> Vec<EntryFailure> {
+    let &UnzipJob {
+        central_directory,
+        selected,
+        options,
+        progress_reporter,
+        operation,
+    } = job;
+    // When failing fast, set on the first failure, after which we don't
+    // start any more files.
+    let failed = AtomicBool::new(false);
+    let process_file = |myzip: &mut ZipArchive<T>, i| {
+        if failed.load(Ordering::Relaxed) || options.cancellation_token.is_cancelled() {
+            return None;
+        }
+        let result = match operation {
+            Operation::Extract(state) => extract_file(
+                myzip,
+                central_directory,
+                i,
+                options,
+                progress_reporter,
+                state,
+            ),
+            Operation::Test => test_file(myzip, central_directory, i, options, progress_reporter),
+        };
+        let e = result.err()?;
+        if options.failure_mode == FailureMode::FailFast {
+            if !failed.swap(true, Ordering::Relaxed) {
+                abort();
+            } else if is_aborted(&e) {
+                // This file was in progress when another failed, and only
+                // failed because we then aborted the download.
+                return None;
+            }
+        }
+        Some(EntryFailure::new(
+            &central_directory.entries[i].name,
+            Some(i),
+            e,
+        ))
+    };
+    let mut failures: Vec<_> = if options.single_threaded {
+        selected
+            .iter()
+            .filter_map(|&i| {
+                process_file(
                     // We theoretically don't need to clone in this case but it
                     // more easily allows us to extract this common code from the
                     // file and URI case.
                     &mut get_ziparchive_clone(),
                     i,
-                    output_directory,
-                    progress_reporter,
-                    directory_creator,
                 )
             })
-            .filter_map(Result::err)
             .collect()
     } else {
         // We use par_bridge here rather than into_par_iter because it turns
@@ -273,78 +1198,264 @@ fn unzip_serial_or_parallel<'a, T: Read + Seek + 'a>(
         // On a device which is CPU-bound or IO-bound (rather than network
         // bound) that's beneficial because we can start to decompress
         // and write data to disk as soon as it arrives from the network.
-        (0..len)
+        selected
+            .iter()
             .par_bridge()
-            .map(|i| {
-                extract_file(
-                    &mut get_ziparchive_clone(),
-                    i,
-                    output_directory,
-                    progress_reporter,
-                    directory_creator,
-                )
-            })
-            .filter_map(Result::err)
+            .filter_map(|&i| process_file(&mut get_ziparchive_clone(), i))
             .collect()
-    }
+    };
+    // Make the order deterministic, regardless of threading.
+    failures.sort_by_key(|failure| failure.index);
+    failures
 }
 
 /// Extracts a file from a zip file, attaching diagnostics to any errors where
 /// possible.
-fn extract_file<T: Read + Seek>(
+fn extract_file<T: Read + Seek + Clone>(
+    myzip: &mut zip::ZipArchive<T>,
+    central_directory: &CentralDirectory,
+    i: usize,
+    options: &UnzipOptions,
+    progress_reporter: &dyn UnzipProgressReporter,
+    state: &ExtractionState,
+) -> Result<()> {
+    if options.strict {
+        let entry = &central_directory.entries[i];
+        check_local_header(myzip.clone().into_inner(), entry)
+            .with_context(|| format!("Failed to extract {}", display_name(&entry.name)))?;
+    }
+    let mut file = myzip.by_index(i)?;
+    let entry = EntryMetadata {
+        index: Some(i),
+        ..EntryMetadata::from(&file)
+    };
+    extract_file_inner(&entry, &mut file, options, progress_reporter, state)
+        .with_context(|| format!("Failed to extract {}", entry.display_name()))?;
+    Ok(())
+}
+
+/// Decompresses a file from a zip file, discarding the data, to check that
+/// it matches its CRC.
+fn test_file<T: Read + Seek + Clone>(
     myzip: &mut zip::ZipArchive<T>,
+    central_directory: &CentralDirectory,
     i: usize,
-    output_directory: &Option<PathBuf>,
+    options: &UnzipOptions,
     progress_reporter: &dyn UnzipProgressReporter,
-    directory_creator: &DirectoryCreator,
 ) -> Result<()> {
-    let file = myzip.by_index(i)?;
-    let name = file
-        .enclosed_name()
+    let entry = &central_directory.entries[i];
+    let display_name = display_name(&entry.name);
+    if options.strict {
+        check_local_header(myzip.clone().into_inner(), entry)
+            .with_context(|| format!("{display_name} is corrupt"))?;
+    }
+    progress_reporter.extraction_starting(&display_name);
+    let mut file = myzip
+        .by_index(i)
+        .with_context(|| format!("{display_name} is corrupt"))?;
+    let mut progress_updater = ProgressUpdater::new(
+        |external_progress| {
+            progress_reporter.bytes_extracted(external_progress);
+        },
+        file.compressed_size(),
+        file.size(),
+        1024 * 1024,
+    );
+    let mut sink = progress_streams::ProgressWriter::new(std::io::sink(), |bytes_written| {
+        progress_reporter.uncompressed_bytes_extracted(bytes_written as u64);
+        progress_updater.progress(bytes_written as u64)
+    });
+    let mut file = CancellableReader::new(&mut file, options.cancellation_token.clone());
+    // zip-rs checks the CRC once we reach the end of the data.
+    std::io::copy(&mut file, &mut sink).with_context(|| format!("{display_name} is corrupt"))?;
+    progress_updater.finish();
+    progress_reporter.extraction_finished(&display_name);
+    Ok(())
+}
+
+/// Everything we need to know about a file within a zip file in order to
+/// extract it, whether that came from the central directory or from a
+/// local file header.
+struct EntryMetadata {
+    /// The name as stored in the zip file.
+    name: String,
+    unix_mode: Option<u32>,
+    compressed_size: u64,
+    uncompressed_size: u64,
+    mtime: Option<SystemTime>,
+    /// Offset of the file data, for logging.
+    data_start: u64,
+    /// The index of the file in the central directory, if known.
+    index: Option<usize>,
+}
+
+impl EntryMetadata {
+    fn is_dir(&self) -> bool {
+        self.name.ends_with('/')
+    }
+
+    /// A name suitable for error messages.
+    fn display_name(&self) -> Cow<'_, str> {
+        display_name(&self.name)
+    }
+}
+
+/// A version of a file's name suitable for error messages.
+fn display_name(name: &str) -> Cow<'_, str> {
+    enclosed_name(name)
         .map(Path::to_string_lossy)
         .unwrap_or_else(|| Cow::Borrowed("<unprintable>"))
-        .to_string();
-    extract_file_inner(file, output_directory, progress_reporter, directory_creator)
-        .with_context(|| format!("Failed to extract {name}"))
 }
 
-/// Extracts a file from a zip file.
+impl From<&ZipFile<'_>> for EntryMetadata {
+    fn from(file: &ZipFile) -> Self {
+        Self {
+            name: file.name().to_string(),
+            unix_mode: file.unix_mode(),
+            compressed_size: file.compressed_size(),
+            uncompressed_size: file.size(),
+            mtime: modification_time(file.extra_data(), file.last_modified()),
+            data_start: file.data_start(),
+            index: None,
+        }
+    }
+}
+
+impl From<&LocalFileHeader> for EntryMetadata {
+    fn from(header: &LocalFileHeader) -> Self {
+        Self {
+            name: header.name.clone(),
+            unix_mode: None,
+            compressed_size: header.compressed_size,
+            uncompressed_size: header.uncompressed_size,
+            mtime: modification_time(&header.extra, header.last_modified),
+            data_start: header.data_start,
+            index: None,
+        }
+    }
+}
+
+/// What [`extract_file_inner`] did with a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExtractOutcome {
+    /// The file, directory or symlink was written, or will be.
+    Written,
+    /// The file was skipped, because of the overwrite or symlink policy.
+    Skipped,
+}
+
+/// Extracts a file from a zip file, given its metadata and a reader which
+/// yields its decompressed contents.
 fn extract_file_inner(
-    mut file: ZipFile,
-    output_directory: &Option<PathBuf>,
+    entry: &EntryMetadata,
+    file: &mut dyn Read,
+    options: &UnzipOptions,
     progress_reporter: &dyn UnzipProgressReporter,
-    directory_creator: &DirectoryCreator,
-) -> Result<()> {
-    let name = file
-        .enclosed_name()
-        .ok_or_else(|| std::io::Error::new(ErrorKind::Unsupported, "path not safe to extract"))?;
-    let out_path = match output_directory {
-        Some(output_directory) => output_directory.join(name),
-        None => PathBuf::from(name),
-    };
+    state: &ExtractionState,
+) -> Result<ExtractOutcome> {
+    let name = enclosed_name(&entry.name)
+        .ok_or_else(|| ClassifiedError::new(FailureKind::Security, "path not safe to extract"))?
+        .to_path_buf();
+    options
+        .limits
+        .check_file(entry.compressed_size, entry.uncompressed_size)?;
+    // The sizes we've just checked may not be truthful, so enforce the limits
+    // on the data itself too.
+    let mut file = LimitedReader::new(
+        file,
+        &options.limits,
+        entry.compressed_size,
+        &state.bytes_extracted,
+    );
+    let mut file = CancellableReader::new(&mut file, options.cancellation_token.clone());
+    let file: &mut dyn Read = &mut file;
+    let sink = state.sink;
     let display_name = name.display().to_string();
     progress_reporter.extraction_starting(&display_name);
     log::info!(
         "Start extract of file at {:x}, length {:x}, name {}",
-        file.data_start(),
-        file.compressed_size(),
+        entry.data_start,
+        entry.compressed_size,
         display_name
     );
-    if file.name().ends_with('/') {
-        directory_creator.create_dir_all(&out_path)?;
+    let mtime = entry.mtime;
+    let is_symlink = is_symlink(entry.unix_mode) && cfg!(unix);
+    if entry.is_dir() {
+        sink.create_dir_all(&name)?;
+        if entry.unix_mode.is_some() || mtime.is_some() {
+            let metadata = FileMetadata {
+                unix_mode: entry.unix_mode,
+                mtime,
+            };
+            state.set_metadata_later(name, entry.index, metadata);
+        }
     } else {
-        if let Some(parent) = out_path.parent() {
-            directory_creator.create_dir_all(parent)?;
-        }
-        let out_file = File::create(&out_path).with_context(|| "Failed to create file")?;
-        // Progress bar strategy. The overall progress across the entire zip file must be
-        // denoted in terms of *compressed* bytes, since at the outset we don't know the uncompressed
-        // size of each file. Yet, within a given file, we update progress based on the bytes
-        // of uncompressed data written, once per 1MB, because that's the information that we happen
-        // to have available. So, calculate how many compressed bytes relate to 1MB of uncompressed
-        // data, and the remainder.
-        let uncompressed_size = file.size();
-        let compressed_size = file.compressed_size();
+        if let Some(parent) = name.parent() {
+            sink.create_dir_all(parent)?;
+        }
+        let uncompressed_size = entry.uncompressed_size;
+        let compressed_size = entry.compressed_size;
+        // If this is a symlink which we can't safely create, we may write
+        // its target into a regular file instead.
+        let mut symlink_target = None;
+        if is_symlink {
+            let target = read_symlink_target(&mut *file)?;
+            let target_path = path_from_bytes(target.clone());
+            if target_is_contained(&name, &target_path) {
+                state.create_symlink_later(PendingSymlink {
+                    name,
+                    index: entry.index,
+                    target: target_path,
+                    metadata: FileMetadata {
+                        unix_mode: entry.unix_mode,
+                        mtime,
+                    },
+                });
+                progress_reporter.bytes_extracted(compressed_size);
+                progress_reporter.uncompressed_bytes_extracted(uncompressed_size);
+                progress_reporter.extraction_finished(&display_name);
+                return Ok(ExtractOutcome::Written);
+            }
+            match options.symlink_policy {
+                SymlinkPolicy::Error => fail!(
+                    Security,
+                    "Symlink target {} may be outside the output directory",
+                    target_path.display()
+                ),
+                SymlinkPolicy::Skip => {
+                    log::warn!(
+                        "Skipping symlink {} with unsafe target {}",
+                        display_name,
+                        target_path.display()
+                    );
+                    progress_reporter.bytes_extracted(compressed_size);
+                    progress_reporter.uncompressed_bytes_extracted(uncompressed_size);
+                    progress_reporter.extraction_finished(&display_name);
+                    return Ok(ExtractOutcome::Skipped);
+                }
+                SymlinkPolicy::WriteAsFile => symlink_target = Some(Cursor::new(target)),
+            }
+        }
+        let metadata = FileMetadata {
+            // A symlink's mode would be wrong for the regular file we write
+            // in its place.
+            unix_mode: entry.unix_mode.filter(|_| !is_symlink),
+            mtime,
+        };
+        let Some(out_file) = sink.create_file(&name, &metadata)? else {
+            log::info!("Skipping existing file {}", display_name);
+            // Count this file as done so that overall progress still
+            // reaches 100%.
+            progress_reporter.bytes_extracted(compressed_size);
+            progress_reporter.uncompressed_bytes_extracted(uncompressed_size);
+            progress_reporter.extraction_finished(&display_name);
+            return Ok(ExtractOutcome::Skipped);
+        };
+        // Progress bar strategy. The overall progress across the entire zip file is mainly denoted
+        // in terms of *compressed* bytes, since that's what we have to fetch. Yet, within a given
+        // file, we update progress based on the bytes of uncompressed data written, once per 1MB,
+        // because that's the information that we happen to have available. So, calculate how many
+        // compressed bytes relate to 1MB of uncompressed data, and the remainder.
         let mut progress_updater = ProgressUpdater::new(
             |external_progress| {
                 progress_reporter.bytes_extracted(external_progress);
@@ -354,65 +1465,140 @@ fn extract_file_inner(
             1024 * 1024,
         );
         let mut out_file = progress_streams::ProgressWriter::new(out_file, |bytes_written| {
+            progress_reporter.uncompressed_bytes_extracted(bytes_written as u64);
             progress_updater.progress(bytes_written as u64)
         });
+        let reader: &mut dyn Read = match &mut symlink_target {
+            Some(target) => target,
+            None => file,
+        };
         // Using a BufWriter here doesn't improve performance even on a VM with
         // spinny disks.
-        std::io::copy(&mut file, &mut out_file).with_context(|| "Failed to write directory")?;
-        progress_updater.finish();
-    }
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::PermissionsExt;
-        if let Some(mode) = file.unix_mode() {
-            std::fs::set_permissions(&out_path, std::fs::Permissions::from_mode(mode))
-                .with_context(|| "Failed to set permissions")?;
+        if let Err(e) = std::io::copy(reader, &mut out_file) {
+            // Don't leave a partially written file behind, whether we were
+            // cancelled, aborted after another file failed, or the data was
+            // corrupt.
+            drop(out_file);
+            if let Err(e) = sink.remove_file(&name) {
+                log::warn!("Failed to remove {}: {e:#}", display_name);
+            }
+            return Err(e).with_context(|| "Failed to write file");
         }
+        drop(out_file);
+        progress_updater.finish();
+        sink.set_metadata(&name, &metadata)?;
     }
     log::info!(
         "Finished extract of file at {:x}, length {:x}, name {}",
-        file.data_start(),
-        file.compressed_size(),
+        entry.data_start,
+        entry.compressed_size,
         display_name
     );
     progress_reporter.extraction_finished(&display_name);
-    Ok(())
+    Ok(ExtractOutcome::Written)
 }
 
-/// An engine used to ensure we don't conflict in creating directories
-/// between threads
-#[derive(Default)]
-struct DirectoryCreator(Mutex<()>);
+/// State shared between the threads of an extraction. It keeps track of
+/// work which must wait until all files are written: creating symlinks and
+/// setting the metadata of directories. And it counts how much data we've
+/// extracted, so that we can enforce [`ExtractionLimits`].
+struct ExtractionState<'a> {
+    sink: &'a dyn ExtractionSink,
+    directories: Mutex<Vec<(PathBuf, Option<usize>, FileMetadata)>>,
+    symlinks: Mutex<Vec<PendingSymlink>>,
+    bytes_extracted: AtomicU64,
+}
 
-impl DirectoryCreator {
-    fn create_dir_all(&self, path: &Path) -> Result<()> {
-        // Fast path - avoid locking if the directory exists
-        if path.exists() {
-            return Ok(());
-        }
-        let _exclusivity = self.0.lock().unwrap();
-        if path.exists() {
-            return Ok(());
+impl<'a> ExtractionState<'a> {
+    fn new(sink: &'a dyn ExtractionSink) -> Self {
+        Self {
+            sink,
+            directories: Mutex::default(),
+            symlinks: Mutex::default(),
+            bytes_extracted: AtomicU64::default(),
         }
-        std::fs::create_dir_all(path).with_context(|| "Failed to create directory")
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::{
-        env::{current_dir, set_current_dir},
-        fs::{read_to_string, File},
-        io::{Cursor, Seek, Write},
-        path::Path,
+    /// Remember to set the metadata of this directory once extraction is
+    /// complete, since writing files into it would change its modification
+    /// time.
+    fn set_metadata_later(&self, path: PathBuf, index: Option<usize>, metadata: FileMetadata) {
+        self.directories
+            .lock()
+            .unwrap()
+            .push((path, index, metadata));
+    }
+
+    /// Remember to create this symlink once all files are written.
+    fn create_symlink_later(&self, symlink: PendingSymlink) {
+        self.symlinks.lock().unwrap().push(symlink);
+    }
+
+    /// Create all the symlinks we were asked to.
+    fn create_symlinks(&self) -> Vec<EntryFailure> {
+        let mut symlinks = std::mem::take(&mut *self.symlinks.lock().unwrap());
+        // Make the order deterministic, regardless of threading.
+        symlinks.sort_by(|a, b| a.name.cmp(&b.name));
+        symlinks
+            .iter()
+            .filter_map(|symlink| {
+                let name = symlink.name.display().to_string();
+                self.sink
+                    .create_symlink(&symlink.name, &symlink.target, &symlink.metadata)
+                    .with_context(|| format!("Failed to extract {name}"))
+                    .map_err(|e| EntryFailure::new(name, symlink.index, e))
+                    .err()
+            })
+            .collect()
+    }
+
+    /// Set the metadata of all the directories we were asked to.
+    fn set_directory_metadata(&self) -> Vec<EntryFailure> {
+        std::mem::take(&mut *self.directories.lock().unwrap())
+            .into_iter()
+            .filter_map(|(path, index, metadata)| {
+                self.sink
+                    .set_metadata(&path, &metadata)
+                    .with_context(|| format!("Failed to set metadata of {}", path.display()))
+                    .map_err(|e| EntryFailure::new(path.display().to_string(), index, e))
+                    .err()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env::{current_dir, set_current_dir},
+        fs::{read_to_string, File},
+        io::{Cursor, Seek, Write},
+        path::Path,
+        sync::{
+            atomic::{AtomicBool, AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+        time::{Duration, SystemTime},
     };
     use tempfile::tempdir;
     use test_log::test;
     use zip::{write::FileOptions, ZipWriter};
 
-    use crate::{NullProgressReporter, UnzipEngine, UnzipOptions};
+    #[cfg(unix)]
+    use crate::SymlinkPolicy;
+    use crate::{
+        CancellationToken, EntryFailure, Error, ExtractionLimits, FailureKind, FailureMode,
+        FilenameFilter, FilenamePattern, HeaderMismatch, MemoryEntry, MemorySink,
+        NullProgressReporter, OverwritePolicy, StreamingUnzipEngine, UnzipEngine, UnzipOptions,
+        UnzipProgressReporter,
+    };
     use ripunzip_test_utils::*;
 
+    use super::{
+        central_directory::{CentralDirectory, CentralDirectoryEntry},
+        total_bytes_expected, total_uncompressed_bytes_expected,
+    };
+
     fn create_zip_file(path: &Path) {
         let file = File::create(path).unwrap();
         create_zip(file)
@@ -434,6 +1620,13 @@ mod tests {
         zip.finish().unwrap();
     }
 
+    fn entry_failures(error: Error) -> Vec<EntryFailure> {
+        match error {
+            Error::Entries(failures) => failures.failures,
+            error => panic!("Unexpected error: {error:?}"),
+        }
+    }
+
     fn check_files_exist(path: &Path) {
         let a = path.join("test/a.txt");
         let b = path.join("b.txt");
@@ -455,6 +1648,7 @@ mod tests {
         let options = UnzipOptions {
             output_directory: None,
             single_threaded: false,
+            ..Default::default()
         };
         UnzipEngine::for_file(zf, options, NullProgressReporter)
             .unwrap()
@@ -474,6 +1668,7 @@ mod tests {
         let options = UnzipOptions {
             output_directory: Some(outdir.clone()),
             single_threaded: false,
+            ..Default::default()
         };
         UnzipEngine::for_file(zf, options, NullProgressReporter)
             .unwrap()
@@ -482,53 +1677,646 @@ mod tests {
         check_files_exist(&outdir);
     }
 
-    use httptest::Server;
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_closes_file() {
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        create_zip_file(&zf);
+        let is_open = || {
+            std::fs::read_dir("/proc/self/fd")
+                .unwrap()
+                .filter_map(|fd| std::fs::read_link(fd.unwrap().path()).ok())
+                .any(|target| target == zf)
+        };
+        let options = UnzipOptions {
+            output_directory: Some(td.path().join("outdir")),
+            ..Default::default()
+        };
+        let engine =
+            UnzipEngine::for_file(File::open(&zf).unwrap(), options, NullProgressReporter).unwrap();
+        assert!(is_open());
+        engine.unzip().unwrap();
+        assert!(!is_open());
+    }
 
     #[test]
-    fn test_extract_from_server() {
+    fn test_extract_from_reader() {
         let td = tempdir().unwrap();
         let mut zip_data = Cursor::new(Vec::new());
         create_zip(&mut zip_data);
-        let body = zip_data.into_inner();
-        println!("Whole zip:");
-        hexdump::hexdump(&body);
-
-        let server = Server::run();
-        set_up_server(&server, body, ServerType::Ranges);
-
         let outdir = td.path().join("outdir");
         let options = UnzipOptions {
             output_directory: Some(outdir.clone()),
             single_threaded: false,
+            ..Default::default()
         };
-        UnzipEngine::for_uri(
-            &server.url("/foo").to_string(),
-            options,
-            None,
+        UnzipEngine::for_reader(zip_data, options, NullProgressReporter)
+            .unwrap()
+            .unzip()
+            .unwrap();
+        check_files_exist(&outdir);
+    }
+
+    #[test]
+    fn test_extract_from_stream() {
+        let td = tempdir().unwrap();
+        let mut zip_data = Cursor::new(Vec::new());
+        create_zip(&mut zip_data);
+        let outdir = td.path().join("outdir");
+        let options = UnzipOptions {
+            output_directory: Some(outdir.clone()),
+            filename_filter: FilenameFilter {
+                include: vec![],
+                exclude: vec![FilenamePattern::glob("test/c.txt").unwrap()],
+            },
+            ..Default::default()
+        };
+        StreamingUnzipEngine::new(&zip_data.into_inner()[..], options, NullProgressReporter)
+            .unzip()
+            .unwrap();
+        assert_eq!(
+            read_to_string(outdir.join("test/a.txt")).unwrap(),
+            "Contents of A\n"
+        );
+        assert_eq!(
+            read_to_string(outdir.join("b.txt")).unwrap(),
+            "Contents of B\n"
+        );
+        assert!(!outdir.join("test/c.txt").exists());
+    }
+
+    #[test]
+    fn test_streaming_progress() {
+        #[derive(Clone, Default)]
+        struct CompressedRecorder {
+            expected: Arc<AtomicU64>,
+            extracted: Arc<AtomicU64>,
+        }
+
+        impl UnzipProgressReporter for CompressedRecorder {
+            fn total_bytes_expected(&self, expected: u64) {
+                self.expected.store(expected, Ordering::SeqCst);
+            }
+
+            fn bytes_extracted(&self, count: u64) {
+                self.extracted.fetch_add(count, Ordering::SeqCst);
+            }
+        }
+
+        let mut zip_data = Cursor::new(Vec::new());
+        create_zip(&mut zip_data);
+        let zip_data = zip_data.into_inner();
+        let recorder = CompressedRecorder::default();
+        StreamingUnzipEngine::new(&zip_data[..], UnzipOptions::default(), recorder.clone())
+            .with_length(zip_data.len() as u64)
+            .unzip_into(&MemorySink::new())
+            .unwrap();
+        assert_eq!(
+            recorder.expected.load(Ordering::SeqCst),
+            zip_data.len() as u64
+        );
+        // The headers and central directory aren't counted.
+        let extracted = recorder.extracted.load(Ordering::SeqCst);
+        assert!(extracted > 0 && extracted < zip_data.len() as u64);
+    }
+
+    #[test]
+    fn test_extract_into_sink() {
+        let mut zip_data = Cursor::new(Vec::new());
+        create_zip(&mut zip_data);
+        let zip_data = zip_data.into_inner();
+        let check = |sink: MemorySink| {
+            let entries = sink.into_entries();
+            assert!(matches!(
+                entries.get(Path::new("test")),
+                Some(MemoryEntry::Directory { .. })
+            ));
+            let files: Vec<_> = entries
+                .into_iter()
+                .filter_map(|(path, entry)| match entry {
+                    MemoryEntry::File { data, metadata } => Some((path, data, metadata)),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(files.len(), 3);
+            assert_eq!(files[0].0, Path::new("b.txt"));
+            assert_eq!(files[0].1, b"Contents of B\n");
+            assert_eq!(files[1].0, Path::new("test/a.txt"));
+            assert_eq!(files[1].1, b"Contents of A\n");
+            assert_eq!(files[2].1, b"Contents of C\n");
+            files[0].2.unix_mode
+        };
+
+        let sink = MemorySink::new();
+        UnzipEngine::for_reader(
+            Cursor::new(zip_data.clone()),
+            UnzipOptions::default(),
             NullProgressReporter,
-            || {},
         )
         .unwrap()
-        .unzip()
+        .unzip_into(&sink)
         .unwrap();
-        check_files_exist(&outdir);
+        let unix_mode = check(sink);
+        assert_eq!(unix_mode.map(|mode| mode & 0o777), Some(0o755));
+
+        // Local headers don't record permissions.
+        let sink = MemorySink::new();
+        StreamingUnzipEngine::new(
+            Cursor::new(zip_data),
+            UnzipOptions::default(),
+            NullProgressReporter,
+        )
+        .unzip_into(&sink)
+        .unwrap();
+        assert_eq!(check(sink), None);
     }
 
-    fn unzip_sample_zip(zip_params: ZipParams, server_type: ServerType) {
+    #[test]
+    fn test_extract_to_memory() {
+        let mut zip_data = Cursor::new(Vec::new());
+        create_zip(&mut zip_data);
+        let zip_data = zip_data.into_inner();
+        let extract = |limits| {
+            let options = UnzipOptions {
+                limits,
+                ..Default::default()
+            };
+            UnzipEngine::for_reader(Cursor::new(zip_data.clone()), options, NullProgressReporter)
+                .unwrap()
+                .extract_to_memory()
+        };
+        let extracted = extract(ExtractionLimits::default()).unwrap();
+        assert_eq!(
+            extracted.files.keys().collect::<Vec<_>>(),
+            [
+                Path::new("b.txt"),
+                Path::new("test/a.txt"),
+                Path::new("test/c.txt")
+            ]
+        );
+        assert_eq!(extracted.files[Path::new("b.txt")], b"Contents of B\n");
+        assert!(extracted.symlinks.is_empty());
+        assert!(extracted.metadata.contains_key(Path::new("test")));
+        assert_eq!(
+            extracted.metadata[Path::new("b.txt")]
+                .unix_mode
+                .map(|mode| mode & 0o777),
+            Some(0o755)
+        );
+        assert!(matches!(
+            extract(ExtractionLimits {
+                max_file_size: Some(13),
+                ..Default::default()
+            }),
+            Err(Error::Limit(_))
+        ));
+    }
+
+    #[test]
+    fn test_extract_to_memory_default_limits() {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("zeros.bin", FileOptions::default()).unwrap();
+        zip.write_all(&[0; 1024 * 1024]).unwrap();
+        let zip_data = zip.finish().unwrap().into_inner();
+        let extract = |limits| {
+            let options = UnzipOptions {
+                limits,
+                ..Default::default()
+            };
+            UnzipEngine::for_reader(Cursor::new(zip_data.clone()), options, NullProgressReporter)
+                .unwrap()
+                .extract_to_memory()
+        };
+        // Far more compressible than we allow by default.
+        assert!(matches!(
+            extract(ExtractionLimits::default()),
+            Err(Error::Limit(_))
+        ));
+        let extracted = extract(ExtractionLimits {
+            max_compression_ratio: Some(u64::MAX),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(extracted.files[Path::new("zeros.bin")].len(), 1024 * 1024);
+    }
+
+    #[test]
+    fn test_extract_with_filter() {
         let td = tempdir().unwrap();
-        let zip_data = ripunzip_test_utils::get_sample_zip(&zip_params);
+        let zf = td.path().join("z.zip");
+        create_zip_file(&zf);
+        let zf = File::open(zf).unwrap();
+        let outdir = td.path().join("outdir");
+        let options = UnzipOptions {
+            output_directory: Some(outdir.clone()),
+            filename_filter: FilenameFilter {
+                include: vec![FilenamePattern::glob("test/*").unwrap()],
+                exclude: vec![FilenamePattern::regex(r"c\.txt$").unwrap()],
+            },
+            ..Default::default()
+        };
+        UnzipEngine::for_file(zf, options, NullProgressReporter)
+            .unwrap()
+            .unzip()
+            .unwrap();
+        assert_eq!(
+            read_to_string(outdir.join("test/a.txt")).unwrap(),
+            "Contents of A\n"
+        );
+        assert!(!outdir.join("b.txt").exists());
+        assert!(!outdir.join("test/c.txt").exists());
+    }
 
-        let server = Server::run();
-        set_up_server(&server, zip_data, server_type);
+    #[test]
+    fn test_overwrite_policy() {
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        create_zip_file(&zf);
+        let outdir = td.path().join("outdir");
+        let extract = |overwrite_policy| {
+            let options = UnzipOptions {
+                output_directory: Some(outdir.clone()),
+                overwrite_policy,
+                ..Default::default()
+            };
+            UnzipEngine::for_file(File::open(&zf).unwrap(), options, NullProgressReporter)
+                .unwrap()
+                .unzip()
+        };
+        extract(OverwritePolicy::Error).unwrap();
+        check_files_exist(&outdir);
+        let b = outdir.join("b.txt");
+        std::fs::write(&b, "Local edits\n").unwrap();
+        extract(OverwritePolicy::Never).unwrap();
+        assert_eq!(read_to_string(&b).unwrap(), "Local edits\n");
+        // The zip file's entries have timestamps in 1980.
+        extract(OverwritePolicy::IfNewer).unwrap();
+        assert_eq!(read_to_string(&b).unwrap(), "Local edits\n");
+        assert!(extract(OverwritePolicy::Error).is_err());
+        assert_eq!(read_to_string(&b).unwrap(), "Local edits\n");
+        extract(OverwritePolicy::Always).unwrap();
+        check_files_exist(&outdir);
+    }
+
+    #[test]
+    fn test_uncompressed_progress() {
+        #[derive(Clone, Default)]
+        struct UncompressedRecorder {
+            expected: Arc<AtomicU64>,
+            extracted: Arc<AtomicU64>,
+        }
+
+        impl UnzipProgressReporter for UncompressedRecorder {
+            fn total_uncompressed_bytes_expected(&self, expected: u64) {
+                self.expected.store(expected, Ordering::SeqCst);
+            }
+
+            fn uncompressed_bytes_extracted(&self, count: u64) {
+                self.extracted.fetch_add(count, Ordering::SeqCst);
+            }
+        }
 
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        create_zip_file(&zf);
         let outdir = td.path().join("outdir");
+        // The second time, every file is skipped, but still counts.
+        for overwrite_policy in [OverwritePolicy::Always, OverwritePolicy::Never] {
+            let recorder = UncompressedRecorder::default();
+            let options = UnzipOptions {
+                output_directory: Some(outdir.clone()),
+                overwrite_policy,
+                ..Default::default()
+            };
+            UnzipEngine::for_file(File::open(&zf).unwrap(), options, recorder.clone())
+                .unwrap()
+                .unzip()
+                .unwrap();
+            assert_eq!(recorder.expected.load(Ordering::SeqCst), 3 * 14);
+            assert_eq!(recorder.extracted.load(Ordering::SeqCst), 3 * 14);
+        }
+    }
+
+    #[test]
+    fn test_expected_totals_saturate() {
+        // Sizes come from the central directory, so a malicious zip file
+        // could make them overflow.
+        let entry = || CentralDirectoryEntry {
+            compressed_size: u64::MAX,
+            uncompressed_size: u64::MAX,
+            ..Default::default()
+        };
+        let central_directory = CentralDirectory {
+            entries: vec![entry(), entry(), entry()],
+            start: 0,
+        };
+        assert_eq!(
+            total_bytes_expected(&central_directory, &[0, 1], 100),
+            u64::MAX
+        );
+        assert_eq!(
+            total_uncompressed_bytes_expected(&central_directory, &[0, 1, 2]),
+            u64::MAX
+        );
+    }
+
+    /// A zip file with one stored file, whose central directory gives its
+    /// uncompressed size and local header offset in a zip64 extra field.
+    fn zip64_zip(uncompressed_size: u64, header_start: u64) -> Vec<u8> {
+        let contents = b"Contents of A\n";
+        let crc = crc32fast::hash(contents);
+        let mut zip_data = Vec::new();
+        zip_data.extend(0x04034b50u32.to_le_bytes());
+        zip_data.extend([20, 0, 0, 0, 0, 0, 0, 0, 0x21, 0]);
+        zip_data.extend(crc.to_le_bytes());
+        zip_data.extend((contents.len() as u32).to_le_bytes());
+        zip_data.extend((contents.len() as u32).to_le_bytes());
+        zip_data.extend([5, 0, 0, 0]);
+        zip_data.extend(b"a.txt");
+        zip_data.extend(contents);
+        let central_directory_start = zip_data.len() as u32;
+        zip_data.extend(0x02014b50u32.to_le_bytes());
+        zip_data.extend([45, 3, 45, 0, 0, 0, 0, 0, 0, 0, 0x21, 0]);
+        zip_data.extend(crc.to_le_bytes());
+        zip_data.extend((contents.len() as u32).to_le_bytes());
+        zip_data.extend(u32::MAX.to_le_bytes());
+        zip_data.extend([5, 0, 20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        zip_data.extend(u32::MAX.to_le_bytes());
+        zip_data.extend(b"a.txt");
+        zip_data.extend([1, 0, 16, 0]);
+        zip_data.extend(uncompressed_size.to_le_bytes());
+        zip_data.extend(header_start.to_le_bytes());
+        let central_directory_size = zip_data.len() as u32 - central_directory_start;
+        zip_data.extend(0x06054b50u32.to_le_bytes());
+        zip_data.extend([0, 0, 0, 0, 1, 0, 1, 0]);
+        zip_data.extend(central_directory_size.to_le_bytes());
+        zip_data.extend(central_directory_start.to_le_bytes());
+        zip_data.extend([0, 0]);
+        zip_data
+    }
+
+    #[test]
+    fn test_free_space_check() {
+        // The file claims to be far bigger than any disk.
+        let zip_data = zip64_zip(1 << 62, 0);
+
+        let td = tempdir().unwrap();
         let options = UnzipOptions {
-            output_directory: Some(outdir),
-            single_threaded: false,
+            output_directory: Some(td.path().join("outdir")),
+            ..Default::default()
+        };
+        let error = UnzipEngine::for_reader(Cursor::new(zip_data), options, NullProgressReporter)
+            .unwrap()
+            .unzip()
+            .unwrap_err();
+        assert!(matches!(error, Error::Limit(_)));
+        assert!(error.to_string().contains("Not enough free space"));
+        assert!(!td.path().join("outdir").exists());
+    }
+
+    #[test]
+    fn test_header_start_near_end_of_address_space() {
+        let zip_data = zip64_zip(14, u64::MAX - 10);
+        let td = tempdir().unwrap();
+        let options = UnzipOptions {
+            output_directory: Some(td.path().join("outdir")),
+            ..Default::default()
+        };
+        let error = UnzipEngine::for_reader(Cursor::new(zip_data), options, NullProgressReporter)
+            .unwrap()
+            .unzip()
+            .unwrap_err();
+        assert!(matches!(error, Error::Archive(_)), "{error:?}");
+    }
+
+    #[test]
+    fn test_extraction_limits() {
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        create_zip_file(&zf);
+        let unzip = |limits| {
+            let options = UnzipOptions {
+                output_directory: Some(td.path().join("outdir")),
+                limits,
+                ..Default::default()
+            };
+            UnzipEngine::for_file(File::open(&zf).unwrap(), options, NullProgressReporter)
+                .unwrap()
+                .unzip()
+        };
+        assert!(matches!(
+            unzip(ExtractionLimits {
+                max_entries: Some(3),
+                ..Default::default()
+            }),
+            Err(Error::Limit(_))
+        ));
+        assert!(matches!(
+            unzip(ExtractionLimits {
+                max_total_size: Some(41),
+                ..Default::default()
+            }),
+            Err(Error::Limit(_))
+        ));
+        assert!(!td.path().join("outdir").exists());
+        unzip(ExtractionLimits {
+            max_entries: Some(4),
+            max_total_size: Some(42),
+            max_file_size: Some(14),
+            max_compression_ratio: Some(1),
+        })
+        .unwrap();
+        check_files_exist(&td.path().join("outdir"));
+    }
+
+    #[test]
+    fn test_duplicate_names() {
+        let mut zip_data = Cursor::new(Vec::new());
+        let mut zip = ZipWriter::new(&mut zip_data);
+        for (name, contents) in [
+            ("a.txt", "First"),
+            ("b.txt", "B"),
+            ("A.TXT", "Second"),
+            ("a.txt", "Third"),
+        ] {
+            zip.start_file(name, FileOptions::default()).unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+        drop(zip);
+        let zip_data = zip_data.into_inner();
+        let server = Server::run();
+        set_up_server(
+            &server,
+            zip_data.clone(),
+            ServerType::ContentLengthButNoRanges,
+        );
+
+        let unzip = |how, case_insensitive_names| {
+            let options = UnzipOptions {
+                case_insensitive_names,
+                ..Default::default()
+            };
+            let sink = MemorySink::new();
+            let result = match how {
+                "seekable" => UnzipEngine::for_reader(
+                    Cursor::new(zip_data.clone()),
+                    options,
+                    NullProgressReporter,
+                )
+                .unwrap()
+                .unzip_into(&sink),
+                "streaming" => {
+                    StreamingUnzipEngine::new(&zip_data[..], options, NullProgressReporter)
+                        .unzip_into(&sink)
+                }
+                _ => UnzipEngine::for_uri(
+                    &server.url("/foo").to_string(),
+                    options,
+                    None,
+                    NullProgressReporter,
+                    || {},
+                )
+                .unwrap()
+                .unzip_into(&sink),
+            };
+            let failed: Vec<_> = match result {
+                Ok(()) => Vec::new(),
+                Err(error) => entry_failures(error)
+                    .into_iter()
+                    .inspect(|failure| assert_eq!(failure.kind, FailureKind::Security))
+                    .map(|failure| failure.name)
+                    .collect(),
+            };
+            let contents: Vec<_> = sink
+                .into_entries()
+                .into_iter()
+                .filter_map(|(path, entry)| match entry {
+                    MemoryEntry::File { data, .. } => {
+                        Some((path.display().to_string(), String::from_utf8(data).unwrap()))
+                    }
+                    _ => None,
+                })
+                .collect();
+            (failed, contents)
+        };
+        let extracted = |files: &[(&str, &str)]| {
+            files
+                .iter()
+                .map(|(name, contents)| (name.to_string(), contents.to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        // Whether or not we can see the central directory first, the first
+        // entry is extracted and the later one fails. Names which differ
+        // only in case are only duplicates if we ask.
+        for how in ["seekable", "streaming", "downloading"] {
+            assert_eq!(
+                unzip(how, false),
+                (
+                    vec!["a.txt".to_string()],
+                    extracted(&[("A.TXT", "Second"), ("a.txt", "First"), ("b.txt", "B")])
+                ),
+                "{how}"
+            );
+            assert_eq!(
+                unzip(how, true),
+                (
+                    vec!["A.TXT".to_string(), "a.txt".to_string()],
+                    extracted(&[("a.txt", "First"), ("b.txt", "B")])
+                ),
+                "{how}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_strict_mode() {
+        let td = tempdir().unwrap();
+        let mut zip_data = Cursor::new(Vec::new());
+        create_zip(&mut zip_data);
+        let zip_data = zip_data.into_inner();
+        let unzip = |zip_data: Vec<u8>, strict| {
+            let options = UnzipOptions {
+                output_directory: Some(td.path().join("outdir")),
+                strict,
+                ..Default::default()
+            };
+            UnzipEngine::for_reader(Cursor::new(zip_data), options, NullProgressReporter)
+                .unwrap()
+                .unzip()
+        };
+        let mismatch = |error| {
+            let failures = entry_failures(error);
+            assert_eq!(failures.len(), 1);
+            assert_eq!(failures[0].kind, FailureKind::Archive);
+            failures[0].error.downcast_ref::<HeaderMismatch>().cloned()
+        };
+        unzip(zip_data.clone(), true).unwrap();
+        // Rename test/ to tost/ in its local header only.
+        let mut renamed = zip_data.clone();
+        renamed[31] = b'o';
+        unzip(renamed.clone(), false).unwrap();
+        let error = unzip(renamed, true).unwrap_err();
+        assert_eq!(
+            mismatch(error),
+            Some(HeaderMismatch::Name {
+                central: "test/".to_string(),
+                local: "tost/".to_string()
+            })
+        );
+        // Corrupt the CRC of test/a.txt in its local header only.
+        let a_header = 30 + "test/".len();
+        let mut corrupted = zip_data;
+        corrupted[a_header + 14] ^= 0xff;
+        let error = unzip(corrupted, true).unwrap_err();
+        assert!(matches!(
+            mismatch(error),
+            Some(HeaderMismatch::Crc32 { .. })
+        ));
+    }
+
+    #[test]
+    fn test_name_decoded_differently_in_local_header() {
+        let mut zip_data = Cursor::new(Vec::new());
+        let mut zip = ZipWriter::new(&mut zip_data);
+        zip.start_file("caf\u{e9}.txt", FileOptions::default())
+            .unwrap();
+        zip.write_all(b"Coffee").unwrap();
+        zip.finish().unwrap();
+        drop(zip);
+        let mut zip_data = zip_data.into_inner();
+        // Clear the UTF-8 flag in the local header only, so that its name
+        // decodes as CP437 instead.
+        assert_eq!(zip_data[7] & 0x08, 0x08);
+        zip_data[7] &= !0x08;
+        let server = Server::run();
+        set_up_server(
+            &server,
+            zip_data.clone(),
+            ServerType::ContentLengthButNoRanges,
+        );
+
+        // The names are the same as stored, so this isn't a mismatch, and
+        // the file is extracted under the central directory's name.
+        let td = tempdir().unwrap();
+        let options = |dir: &str| UnzipOptions {
+            output_directory: Some(td.path().join(dir)),
+            strict: true,
+            ..Default::default()
         };
+        UnzipEngine::for_reader(
+            Cursor::new(zip_data),
+            options("seekable"),
+            NullProgressReporter,
+        )
+        .unwrap()
+        .unzip()
+        .unwrap();
         UnzipEngine::for_uri(
             &server.url("/foo").to_string(),
-            options,
+            options("downloading"),
             None,
             NullProgressReporter,
             || {},
@@ -536,6 +2324,803 @@ mod tests {
         .unwrap()
         .unzip()
         .unwrap();
+        for dir in ["seekable", "downloading"] {
+            let names: Vec<_> = std::fs::read_dir(td.path().join(dir))
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect();
+            assert_eq!(names, ["caf\u{e9}.txt"], "{dir}");
+        }
+    }
+
+    #[test]
+    fn test_unsupported_compression_method() {
+        let mut zip_data = Cursor::new(Vec::new());
+        create_zip(&mut zip_data);
+        let mut zip_data = zip_data.into_inner();
+        // Give test/a.txt a compression method nobody has heard of, in both
+        // its local header and its central directory record.
+        let a_header = 30 + "test/".len();
+        zip_data[a_header + 8] = 98;
+        let a_record = zip_data
+            .windows(4)
+            .enumerate()
+            .filter(|(_, window)| *window == 0x02014b50u32.to_le_bytes())
+            .nth(1)
+            .unwrap()
+            .0;
+        zip_data[a_record + 10] = 98;
+        let td = tempdir().unwrap();
+        let options = |dir: &str| UnzipOptions {
+            output_directory: Some(td.path().join(dir)),
+            ..Default::default()
+        };
+        let seekable = UnzipEngine::for_reader(
+            Cursor::new(zip_data.clone()),
+            options("seekable"),
+            NullProgressReporter,
+        )
+        .unwrap()
+        .unzip();
+        let streaming =
+            StreamingUnzipEngine::new(&zip_data[..], options("streaming"), NullProgressReporter)
+                .unzip();
+        for result in [seekable, streaming] {
+            let failures = entry_failures(result.unwrap_err());
+            assert_eq!(failures.len(), 1);
+            assert_eq!(failures[0].name, "test/a.txt");
+            assert_eq!(failures[0].kind, FailureKind::Unsupported);
+        }
+    }
+
+    #[test]
+    fn test_integrity_check() {
+        let td = tempdir().unwrap();
+        let mut zip_data = Cursor::new(Vec::new());
+        create_zip(&mut zip_data);
+        let mut zip_data = zip_data.into_inner();
+        let test = |zip_data: &[u8]| {
+            let options = UnzipOptions {
+                output_directory: Some(td.path().join("outdir")),
+                ..Default::default()
+            };
+            UnzipEngine::for_reader(
+                Cursor::new(zip_data.to_vec()),
+                options,
+                NullProgressReporter,
+            )
+            .unwrap()
+            .test()
+        };
+        test(&zip_data).unwrap();
+        // Corrupt the data of two of the files.
+        for contents in [&b"Contents of A"[..], b"Contents of C"] {
+            let offset = zip_data
+                .windows(contents.len())
+                .position(|window| window == contents)
+                .unwrap();
+            zip_data[offset] = b'X';
+        }
+        let failures = entry_failures(test(&zip_data).unwrap_err());
+        let summary: Vec<_> = failures
+            .iter()
+            .map(|failure| {
+                (
+                    failure.name.as_str(),
+                    failure.index,
+                    failure.kind,
+                    failure.error.to_string(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (
+                    "test/a.txt",
+                    Some(1),
+                    FailureKind::Archive,
+                    "test/a.txt is corrupt".to_string()
+                ),
+                (
+                    "test/c.txt",
+                    Some(3),
+                    FailureKind::Archive,
+                    "test/c.txt is corrupt".to_string()
+                ),
+            ]
+        );
+        assert!(!td.path().join("outdir").exists());
+    }
+
+    #[test]
+    fn test_fail_fast() {
+        let td = tempdir().unwrap();
+        let mut zip_data = Cursor::new(Vec::new());
+        create_zip(&mut zip_data);
+        let mut zip_data = zip_data.into_inner();
+        // Corrupt the data of test/a.txt and test/c.txt.
+        for contents in [&b"Contents of A"[..], b"Contents of C"] {
+            let offset = zip_data
+                .windows(contents.len())
+                .position(|window| window == contents)
+                .unwrap();
+            zip_data[offset] = b'X';
+        }
+        let unzip = |outdir: &str, failure_mode| {
+            let options = UnzipOptions {
+                output_directory: Some(td.path().join(outdir)),
+                single_threaded: true,
+                failure_mode,
+                ..Default::default()
+            };
+            let engine = UnzipEngine::for_reader(
+                Cursor::new(zip_data.clone()),
+                options,
+                NullProgressReporter,
+            )
+            .unwrap();
+            entry_failures(engine.unzip().unwrap_err())
+        };
+        let failures = unzip("keepgoing", FailureMode::KeepGoing);
+        assert_eq!(failures.len(), 2);
+        assert!(td.path().join("keepgoing/b.txt").exists());
+        // Corrupt files aren't left half written.
+        assert!(!td.path().join("keepgoing/test/a.txt").exists());
+        assert!(!td.path().join("keepgoing/test/c.txt").exists());
+        let failures = unzip("failfast", FailureMode::FailFast);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].name, "test/a.txt");
+        assert!(!td.path().join("failfast/b.txt").exists());
+        assert!(!td.path().join("failfast/test/a.txt").exists());
+    }
+
+    #[test]
+    fn test_cancellation() {
+        struct Canceller(CancellationToken);
+
+        impl UnzipProgressReporter for Canceller {
+            fn uncompressed_bytes_extracted(&self, _count: u64) {
+                self.0.cancel();
+            }
+        }
+
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        let mut zip = ZipWriter::new(File::create(&zf).unwrap());
+        zip.start_file("big.bin", FileOptions::default()).unwrap();
+        zip.write_all(&[7u8; 1 << 20]).unwrap();
+        zip.start_file("after.txt", FileOptions::default()).unwrap();
+        zip.write_all(b"Contents of after\n").unwrap();
+        zip.finish().unwrap();
+
+        let outdir = td.path().join("outdir");
+        let token = CancellationToken::new();
+        let options = UnzipOptions {
+            output_directory: Some(outdir.clone()),
+            single_threaded: true,
+            cancellation_token: token.clone(),
+            ..Default::default()
+        };
+        let engine =
+            UnzipEngine::for_file(File::open(&zf).unwrap(), options, Canceller(token)).unwrap();
+        assert!(matches!(engine.unzip(), Err(Error::Cancelled)));
+        // The partly written file is removed, and nothing after it started.
+        assert!(!outdir.join("big.bin").exists());
+        assert!(!outdir.join("after.txt").exists());
+    }
+
+    #[test]
+    fn test_restores_modification_times() {
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        let mut zip = ZipWriter::new(File::create(&zf).unwrap());
+        let dir_time = zip::DateTime::from_date_and_time(2001, 9, 9, 1, 46, 40).unwrap();
+        let file_time = zip::DateTime::from_date_and_time(2001, 9, 9, 1, 46, 42).unwrap();
+        zip.add_directory("test/", FileOptions::default().last_modified_time(dir_time))
+            .unwrap();
+        zip.start_file(
+            "test/a.txt",
+            FileOptions::default().last_modified_time(file_time),
+        )
+        .unwrap();
+        zip.write_all(b"Contents of A\n").unwrap();
+        zip.finish().unwrap();
+
+        let outdir = td.path().join("outdir");
+        let options = UnzipOptions {
+            output_directory: Some(outdir.clone()),
+            ..Default::default()
+        };
+        UnzipEngine::for_file(File::open(&zf).unwrap(), options, NullProgressReporter)
+            .unwrap()
+            .unzip()
+            .unwrap();
+        let mtime = |path: &str| {
+            std::fs::metadata(outdir.join(path))
+                .unwrap()
+                .modified()
+                .unwrap()
+        };
+        assert_eq!(
+            mtime("test"),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000)
+        );
+        assert_eq!(
+            mtime("test/a.txt"),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_002)
+        );
+    }
+
+    #[cfg(unix)]
+    fn unzip_with_symlinks(
+        outdir: &Path,
+        symlinks: &[(&str, &str)],
+        symlink_policy: SymlinkPolicy,
+    ) -> Result<(), Error> {
+        let mut zip_data = Cursor::new(Vec::new());
+        let mut zip = ZipWriter::new(&mut zip_data);
+        zip.start_file("test/a.txt", FileOptions::default())
+            .unwrap();
+        zip.write_all(b"Contents of A\n").unwrap();
+        for (name, target) in symlinks {
+            zip.add_symlink(*name, *target, FileOptions::default())
+                .unwrap();
+        }
+        zip.finish().unwrap();
+        drop(zip);
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        std::fs::write(&zf, zip_data.into_inner()).unwrap();
+        let options = UnzipOptions {
+            output_directory: Some(outdir.to_path_buf()),
+            symlink_policy,
+            ..Default::default()
+        };
+        UnzipEngine::for_file(File::open(zf).unwrap(), options, NullProgressReporter)
+            .unwrap()
+            .unzip()
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_extract_symlinks() {
+        let td = tempdir().unwrap();
+        let outdir = td.path().join("outdir");
+        unzip_with_symlinks(
+            &outdir,
+            &[("test/link", "a.txt"), ("test/up", "..")],
+            SymlinkPolicy::Error,
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read_link(outdir.join("test/link")).unwrap(),
+            Path::new("a.txt")
+        );
+        assert_eq!(
+            read_to_string(outdir.join("test/link")).unwrap(),
+            "Contents of A\n"
+        );
+        assert_eq!(
+            read_to_string(outdir.join("test/up/test/a.txt")).unwrap(),
+            "Contents of A\n"
+        );
+        // Extracting again replaces the symlinks.
+        unzip_with_symlinks(&outdir, &[("test/link", "a.txt")], SymlinkPolicy::Error).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_unsafe_symlinks() {
+        let td = tempdir().unwrap();
+        let outdir = td.path().join("outdir");
+        for target in ["../outside", "/etc/passwd", "test/../../outside"] {
+            assert!(
+                unzip_with_symlinks(&outdir, &[("evil", target)], SymlinkPolicy::Error).is_err()
+            );
+            assert!(std::fs::symlink_metadata(outdir.join("evil")).is_err());
+        }
+        unzip_with_symlinks(&outdir, &[("evil", "../outside")], SymlinkPolicy::Skip).unwrap();
+        assert!(std::fs::symlink_metadata(outdir.join("evil")).is_err());
+        unzip_with_symlinks(
+            &outdir,
+            &[("evil", "../outside")],
+            SymlinkPolicy::WriteAsFile,
+        )
+        .unwrap();
+        assert_eq!(read_to_string(outdir.join("evil")).unwrap(), "../outside");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_refuses_to_write_through_symlinks() {
+        let td = tempdir().unwrap();
+        let outdir = td.path().join("outdir");
+        let elsewhere = td.path().join("elsewhere");
+        std::fs::create_dir_all(&outdir).unwrap();
+        std::fs::create_dir_all(&elsewhere).unwrap();
+        std::os::unix::fs::symlink(&elsewhere, outdir.join("test")).unwrap();
+        assert!(unzip_with_symlinks(&outdir, &[], SymlinkPolicy::Error).is_err());
+        assert!(!elsewhere.join("a.txt").exists());
+    }
+
+    #[test]
+    fn test_entries() {
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        create_zip_file(&zf);
+        let expected_crc32 = zip::ZipArchive::new(File::open(&zf).unwrap())
+            .unwrap()
+            .by_index(1)
+            .unwrap()
+            .crc32();
+        let zf = File::open(zf).unwrap();
+        let entries = UnzipEngine::for_file(zf, UnzipOptions::default(), NullProgressReporter)
+            .unwrap()
+            .entries()
+            .unwrap();
+        let names: Vec<_> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["test/", "test/a.txt", "b.txt", "test/c.txt"]);
+        assert!(entries[0].is_dir);
+        assert!(!entries[1].is_dir);
+        assert_eq!(entries[1].size, 14);
+        assert_eq!(entries[1].compressed_size, 14);
+        assert_eq!(entries[1].compression_method_name(), "stored");
+        assert_eq!(entries[1].crc32, expected_crc32);
+        assert_eq!(entries[1].unix_mode, Some(0o100755));
+        assert!(!entries[1].encrypted);
+        assert!(!entries[1].zip64);
+        assert_eq!(entries[1].data_start, None);
+    }
+
+    #[test]
+    fn test_entries_with_data_start() {
+        let td = tempdir().unwrap();
+        let zf = td.path().join("z.zip");
+        create_zip_file(&zf);
+        let zip_data = std::fs::read(&zf).unwrap();
+        let zf = File::open(zf).unwrap();
+        let entries = UnzipEngine::for_file(zf, UnzipOptions::default(), NullProgressReporter)
+            .unwrap()
+            .entries_with_data_start()
+            .unwrap();
+        // The files are stored, so their contents can be found at data_start.
+        let data_start = entries[2].data_start.unwrap() as usize;
+        assert_eq!(&zip_data[data_start..data_start + 14], b"Contents of B\n");
+        assert!(entries[2].header_start < entries[2].data_start.unwrap());
+    }
+
+    use httptest::Server;
+
+    fn list_from_server(server_type: ServerType) {
+        let mut zip_data = Cursor::new(Vec::new());
+        create_zip(&mut zip_data);
+        let server = Server::run();
+        set_up_server(&server, zip_data.into_inner(), server_type);
+        let entries = UnzipEngine::for_uri(
+            &server.url("/foo").to_string(),
+            UnzipOptions::default(),
+            None,
+            NullProgressReporter,
+            || {},
+        )
+        .unwrap()
+        .entries()
+        .unwrap();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[2].name, "b.txt");
+    }
+
+    #[test]
+    fn test_list_from_server() {
+        list_from_server(ServerType::Ranges)
+    }
+
+    #[test]
+    fn test_list_from_no_range_server() {
+        list_from_server(ServerType::ContentLengthButNoRanges)
+    }
+
+    /// Serves ranges of a body, as [`set_up_server`] does, counting how many
+    /// bytes it's asked for.
+    struct CountingResponse {
+        response: RangeAwareResponse,
+        len: u64,
+        sent: Arc<AtomicU64>,
+    }
+
+    impl httptest::responders::Responder for CountingResponse {
+        fn respond<'a>(
+            &mut self,
+            req: &'a httptest::http::Request<httptest::bytes::Bytes>,
+        ) -> std::pin::Pin<
+            Box<
+                dyn std::future::Future<Output = httptest::http::Response<hyper::Body>> + Send + 'a,
+            >,
+        > {
+            let range = req
+                .headers()
+                .get(http::header::RANGE)
+                .and_then(|range| range.to_str().ok()?.strip_prefix("bytes="))
+                .and_then(|range| range.split_once('-'))
+                .and_then(|(from, to)| Some((from.parse::<u64>().ok()?, to.parse::<u64>().ok()?)));
+            let sent = match range {
+                Some((from, to)) => (to + 1).min(self.len) - from,
+                None => self.len,
+            };
+            self.sent.fetch_add(sent, Ordering::SeqCst);
+            self.response.respond(req)
+        }
+    }
+
+    #[test]
+    fn test_entries_with_data_start_from_server() {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        let big: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+        for name in ["a.bin", "b.bin", "c.bin"] {
+            zip.start_file(name, options).unwrap();
+            zip.write_all(&big).unwrap();
+        }
+        let zip_data = zip.finish().unwrap().into_inner();
+        let len = zip_data.len() as u64;
+        let server = Server::run();
+        server.expect(
+            httptest::Expectation::matching(httptest::matchers::request::method_path(
+                "HEAD", "/foo",
+            ))
+            .times(..)
+            .respond_with(RangeAwareResponse::new(
+                200,
+                RangeAwareResponseType::LengthOnly(zip_data.len()),
+            )),
+        );
+        let sent = Arc::new(AtomicU64::new(0));
+        server.expect(
+            httptest::Expectation::matching(httptest::matchers::request::method_path(
+                "GET", "/foo",
+            ))
+            .times(..)
+            .respond_with(CountingResponse {
+                response: RangeAwareResponse::new(
+                    206,
+                    RangeAwareResponseType::Body(zip_data.clone().into()),
+                ),
+                len,
+                sent: sent.clone(),
+            }),
+        );
+        let entries = UnzipEngine::for_uri(
+            &server.url("/foo").to_string(),
+            UnzipOptions::default(),
+            None,
+            NullProgressReporter,
+            || {},
+        )
+        .unwrap()
+        .entries_with_data_start()
+        .unwrap();
+        let data_start = entries[1].data_start.unwrap() as usize;
+        assert_eq!(&zip_data[data_start..data_start + 100], &big[..100]);
+        // Only the end of the zip file and the local headers are fetched,
+        // not the files in between.
+        assert!(sent.load(Ordering::SeqCst) < len / 2);
+    }
+
+    fn extract_from_server(server_type: ServerType) {
+        let td = tempdir().unwrap();
+        let mut zip_data = Cursor::new(Vec::new());
+        create_zip(&mut zip_data);
+        let body = zip_data.into_inner();
+        println!("Whole zip:");
+        hexdump::hexdump(&body);
+
+        let server = Server::run();
+        set_up_server(&server, body, server_type);
+
+        let outdir = td.path().join("outdir");
+        let options = UnzipOptions {
+            output_directory: Some(outdir.clone()),
+            single_threaded: false,
+            ..Default::default()
+        };
+        UnzipEngine::for_uri(
+            &server.url("/foo").to_string(),
+            options,
+            None,
+            NullProgressReporter,
+            || {},
+        )
+        .unwrap()
+        .unzip()
+        .unwrap();
+        check_files_exist(&outdir);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let metadata = std::fs::metadata(outdir.join("b.txt")).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o755);
+        }
+    }
+
+    #[test]
+    fn test_extract_from_server() {
+        extract_from_server(ServerType::Ranges)
+    }
+
+    #[test]
+    fn test_extract_from_no_range_server() {
+        // Files are extracted as they download, then their permissions are
+        // restored from the central directory.
+        extract_from_server(ServerType::ContentLengthButNoRanges)
+    }
+
+    #[test]
+    fn test_download_progress() {
+        #[derive(Clone, Default)]
+        struct DownloadRecorder {
+            length: Arc<Mutex<Option<u64>>>,
+            downloaded: Arc<AtomicU64>,
+            finished: Arc<AtomicBool>,
+        }
+
+        impl UnzipProgressReporter for DownloadRecorder {
+            fn download_starting(&self, length: Option<u64>) {
+                *self.length.lock().unwrap() = length;
+            }
+
+            fn bytes_downloaded(&self, count: u64) {
+                self.downloaded.fetch_add(count, Ordering::SeqCst);
+            }
+
+            fn download_finished(&self) {
+                self.finished.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let td = tempdir().unwrap();
+        let mut zip_data = Cursor::new(Vec::new());
+        create_zip(&mut zip_data);
+        let zip_data = zip_data.into_inner();
+        let zip_length = zip_data.len() as u64;
+        let server = Server::run();
+        set_up_server(&server, zip_data, ServerType::ContentLengthButNoRanges);
+        let recorder = DownloadRecorder::default();
+        let options = UnzipOptions {
+            output_directory: Some(td.path().to_path_buf()),
+            ..Default::default()
+        };
+        UnzipEngine::for_uri(
+            &server.url("/foo").to_string(),
+            options,
+            None,
+            recorder.clone(),
+            || {},
+        )
+        .unwrap()
+        .unzip()
+        .unwrap();
+        assert_eq!(*recorder.length.lock().unwrap(), Some(zip_length));
+        assert_eq!(recorder.downloaded.load(Ordering::SeqCst), zip_length);
+        assert!(recorder.finished.load(Ordering::SeqCst));
+
+        // The download is reported as finished even if it fails partway
+        // through a file.
+        let mut truncated = Cursor::new(Vec::new());
+        create_zip(&mut truncated);
+        let mut truncated = truncated.into_inner();
+        truncated.truncate(80);
+        let server = Server::run();
+        set_up_server(&server, truncated, ServerType::ContentLengthButNoRanges);
+        let recorder = DownloadRecorder::default();
+        let options = UnzipOptions {
+            output_directory: Some(td.path().join("truncated")),
+            ..Default::default()
+        };
+        UnzipEngine::for_uri(
+            &server.url("/foo").to_string(),
+            options,
+            None,
+            recorder.clone(),
+            || {},
+        )
+        .unwrap()
+        .unzip()
+        .unwrap_err();
+        assert!(recorder.finished.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_extract_symlinks_from_no_range_server() {
+        let td = tempdir().unwrap();
+        let mut zip_data = Cursor::new(Vec::new());
+        let mut zip = ZipWriter::new(&mut zip_data);
+        zip.start_file("a.txt", FileOptions::default()).unwrap();
+        zip.write_all(b"Contents of A\n").unwrap();
+        zip.add_symlink("link", "a.txt", FileOptions::default())
+            .unwrap();
+        zip.add_symlink("escape", "../a.txt", FileOptions::default())
+            .unwrap();
+        zip.finish().unwrap();
+        drop(zip);
+        let server = Server::run();
+        set_up_server(
+            &server,
+            zip_data.into_inner(),
+            ServerType::ContentLengthButNoRanges,
+        );
+        let outdir = td.path().join("outdir");
+        let options = UnzipOptions {
+            output_directory: Some(outdir.clone()),
+            ..Default::default()
+        };
+        assert!(UnzipEngine::for_uri(
+            &server.url("/foo").to_string(),
+            options,
+            None,
+            NullProgressReporter,
+            || {},
+        )
+        .unwrap()
+        .unzip()
+        .is_err());
+        assert_eq!(
+            std::fs::read_link(outdir.join("link")).unwrap(),
+            Path::new("a.txt")
+        );
+        // The unsafe symlink was streamed as a regular file, which must be
+        // removed again.
+        assert!(!outdir.join("escape").exists());
+    }
+
+    fn unzip_sample_zip(zip_params: ZipParams, server_type: ServerType) {
+        let td = tempdir().unwrap();
+        let zip_data = ripunzip_test_utils::get_sample_zip(&zip_params);
+
+        let server = Server::run();
+        set_up_server(&server, zip_data, server_type);
+
+        let outdir = td.path().join("outdir");
+        let options = UnzipOptions {
+            output_directory: Some(outdir),
+            single_threaded: false,
+            ..Default::default()
+        };
+        UnzipEngine::for_uri(
+            &server.url("/foo").to_string(),
+            options,
+            None,
+            NullProgressReporter,
+            || {},
+        )
+        .unwrap()
+        .unzip()
+        .unwrap();
+    }
+
+    fn extract_with_filter_from_server(server_type: ServerType) {
+        let td = tempdir().unwrap();
+        let zip_data = ripunzip_test_utils::get_sample_zip(&ZipParams::new(
+            FileSizes::Variable,
+            15,
+            zip::CompressionMethod::Deflated,
+        ));
+        let server = Server::run();
+        set_up_server(&server, zip_data, server_type);
+
+        let outdir = td.path().join("outdir");
+        let options = UnzipOptions {
+            output_directory: Some(outdir.clone()),
+            filename_filter: FilenameFilter {
+                include: vec![FilenamePattern::glob("1?.txt").unwrap()],
+                exclude: vec![FilenamePattern::glob("12.txt").unwrap()],
+            },
+            ..Default::default()
+        };
+        UnzipEngine::for_uri(
+            &server.url("/foo").to_string(),
+            options,
+            None,
+            NullProgressReporter,
+            || {},
+        )
+        .unwrap()
+        .unzip()
+        .unwrap();
+        let mut extracted: Vec<_> = std::fs::read_dir(&outdir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        extracted.sort();
+        assert_eq!(
+            extracted,
+            ["10.txt", "11.txt", "13.txt", "14.txt"].map(String::from)
+        );
+        assert!(std::fs::metadata(outdir.join("14.txt")).unwrap().len() > 0);
+    }
+
+    #[test]
+    fn test_extract_with_filter_from_ranges_server() {
+        extract_with_filter_from_server(ServerType::Ranges)
+    }
+
+    #[test]
+    fn test_extract_with_filter_from_no_range_server() {
+        extract_with_filter_from_server(ServerType::ContentLengthButNoRanges)
+    }
+
+    #[test]
+    fn test_corrupt_file_error() {
+        let mut zip_data = Cursor::new(Vec::new());
+        create_zip(&mut zip_data);
+        let mut zip_data = zip_data.into_inner();
+        let contents = zip_data
+            .windows(13)
+            .position(|window| window == b"Contents of B")
+            .unwrap();
+        zip_data[contents] = b'X';
+        let sink = MemorySink::new();
+        let error = UnzipEngine::for_reader(
+            Cursor::new(zip_data),
+            UnzipOptions::default(),
+            NullProgressReporter,
+        )
+        .unwrap()
+        .unzip_into(&sink)
+        .unwrap_err();
+        let failures = entry_failures(error);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].kind, FailureKind::Archive);
+        assert_eq!(
+            format!("{:#}", failures[0].error),
+            "Failed to extract b.txt: Failed to write file: Invalid checksum"
+        );
+    }
+
+    #[test]
+    fn test_mismatched_local_header_from_no_range_server() {
+        let td = tempdir().unwrap();
+        let mut zip_data = Cursor::new(Vec::new());
+        create_zip(&mut zip_data);
+        let mut zip_data = zip_data.into_inner();
+        // Rename b.txt to d.txt in its local header only. The file is
+        // extracted under that name before we see the central directory.
+        let local_name = zip_data
+            .windows(5)
+            .position(|window| window == b"b.txt")
+            .unwrap();
+        zip_data[local_name] = b'd';
+        let server = Server::run();
+        set_up_server(&server, zip_data, ServerType::ContentLengthButNoRanges);
+        let options = UnzipOptions {
+            output_directory: Some(td.path().to_path_buf()),
+            ..Default::default()
+        };
+        let error = UnzipEngine::for_uri(
+            &server.url("/foo").to_string(),
+            options,
+            None,
+            NullProgressReporter,
+            || {},
+        )
+        .unwrap()
+        .unzip()
+        .unwrap_err();
+        let failures = entry_failures(error);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].name, "b.txt");
+        assert_eq!(failures[0].kind, FailureKind::Archive);
+        assert_eq!(
+            failures[0].error.downcast_ref::<HeaderMismatch>(),
+            Some(&HeaderMismatch::Name {
+                central: "b.txt".to_string(),
+                local: "d.txt".to_string()
+            })
+        );
+        assert!(!td.path().join("d.txt").exists());
+        assert!(!td.path().join("b.txt").exists());
+        assert!(td.path().join("test/a.txt").exists());
     }
 
     #[test]
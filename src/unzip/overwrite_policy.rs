@@ -0,0 +1,123 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{io::ErrorKind, path::Path, time::SystemTime};
+
+use anyhow::{Context, Result};
+
+/// What to do when a file we're about to extract already exists.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverwritePolicy {
+    /// Leave the existing file alone and skip extracting this one. Whether
+    /// the file exists is checked as it's created, so nothing which appears
+    /// in between is overwritten.
+    Never,
+    /// Replace the existing file.
+    #[default]
+    Always,
+    /// Replace the existing file only if the file in the zip file was
+    /// modified more recently. If the modification time of either can't be
+    /// determined, the existing file is left alone.
+    ///
+    /// Zip files which only record MS-DOS timestamps don't say which time
+    /// zone they're in, and we take them to be UTC, so for those the
+    /// comparison may be off by the local offset from UTC.
+    IfNewer,
+    /// Fail to extract this file. Whether the file exists is checked as it's
+    /// created, so nothing can appear in between.
+    Error,
+}
+
+impl OverwritePolicy {
+    /// Decide whether we should write to `out_path`. `entry_mtime` gives the
+    /// modification time of the file within the zip file, and is only
+    /// called if needed. For [`Self::Never`] and [`Self::Error`], this is
+    /// always true, and it's up to the caller to create the file such that
+    /// it fails if it exists, then to skip it for [`Self::Never`].
+    pub(crate) fn should_write(
+        self,
+        out_path: &Path,
+        entry_mtime: impl FnOnce() -> Option<SystemTime>,
+    ) -> Result<bool> {
+        if self != Self::IfNewer {
+            return Ok(true);
+        }
+        let metadata = match std::fs::symlink_metadata(out_path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(true),
+            Err(e) => return Err(e).with_context(|| "Failed to check for existing file"),
+        };
+        Ok(match (entry_mtime(), metadata.modified()) {
+            (Some(entry_mtime), Ok(existing_mtime)) => entry_mtime > existing_mtime,
+            _ => false,
+        })
+    }
+
+    /// Whether anything already at the output path should be removed
+    /// before writing there.
+    pub(crate) fn replaces_existing(self) -> bool {
+        matches!(self, Self::Always | Self::IfNewer)
+    }
+
+    /// Whether to skip a file, rather than fail, if creating it finds
+    /// something already there.
+    pub(crate) fn skips_existing(self) -> bool {
+        self == Self::Never
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+    use tempfile::tempdir;
+    use test_log::test;
+
+    use super::OverwritePolicy;
+
+    #[test]
+    fn test_overwrite_policies() {
+        let td = tempdir().unwrap();
+        let missing = td.path().join("missing");
+        let existing = td.path().join("existing");
+        std::fs::write(&existing, "").unwrap();
+        let existing_mtime = std::fs::metadata(&existing).unwrap().modified().unwrap();
+        let older = || Some(existing_mtime - Duration::from_secs(60));
+        let newer = || Some(existing_mtime + Duration::from_secs(60));
+
+        for policy in [
+            OverwritePolicy::Never,
+            OverwritePolicy::Always,
+            OverwritePolicy::IfNewer,
+            OverwritePolicy::Error,
+        ] {
+            assert!(policy.should_write(&missing, older).unwrap());
+        }
+        assert!(OverwritePolicy::Always
+            .should_write(&existing, older)
+            .unwrap());
+        assert!(!OverwritePolicy::Never.replaces_existing());
+        assert!(OverwritePolicy::Never.skips_existing());
+        assert!(OverwritePolicy::IfNewer
+            .should_write(&existing, newer)
+            .unwrap());
+        assert!(!OverwritePolicy::IfNewer
+            .should_write(&existing, older)
+            .unwrap());
+        assert!(!OverwritePolicy::IfNewer
+            .should_write(&existing, || None::<SystemTime>)
+            .unwrap());
+        // Existence is checked as the file is created instead.
+        assert!(OverwritePolicy::Never
+            .should_write(&existing, newer)
+            .unwrap());
+        assert!(OverwritePolicy::Error
+            .should_write(&existing, newer)
+            .unwrap());
+        assert!(!OverwritePolicy::Error.replaces_existing());
+    }
+}
@@ -29,19 +29,17 @@ impl<F: Fn(u64)> ProgressUpdater<F> {
         per_update_internal: u64,
     ) -> Self {
         let per_update_internal = min(internal_total, per_update_internal);
-        let total_updates_expected = if per_update_internal == 0 {
-            0
-        } else {
-            internal_total / per_update_internal
-        };
-        let (update_external_amount, remainder_external) = if total_updates_expected == 0 {
-            (0, external_total)
-        } else {
-            (
-                external_total / total_updates_expected,
-                external_total % total_updates_expected,
-            )
-        };
+        let total_updates_expected = internal_total
+            .checked_div(per_update_internal)
+            .unwrap_or_default();
+        let (update_external_amount, remainder_external) =
+            match external_total.checked_div(total_updates_expected) {
+                None => (0, external_total),
+                Some(update_external_amount) => (
+                    update_external_amount,
+                    external_total % total_updates_expected,
+                ),
+            };
         Self {
             callback,
             internal_progress: 0u64,
@@ -62,11 +60,10 @@ impl<F: Fn(u64)> ProgressUpdater<F> {
     }
 
     fn send_due_updates(&mut self) {
-        let updates_due = if self.per_update_internal == 0 {
-            0
-        } else {
-            self.internal_progress / self.per_update_internal
-        };
+        let updates_due = self
+            .internal_progress
+            .checked_div(self.per_update_internal)
+            .unwrap_or_default();
         while updates_due > self.external_updates_sent {
             (self.callback)(self.update_external_amount);
             self.external_updates_sent += 1;
@@ -0,0 +1,175 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{cmp::min, ops::Range};
+
+use super::central_directory::{CentralDirectory, LOCAL_FILE_HEADER_SIZE};
+
+/// Spans of the zip file which are separated by fewer than this many bytes
+/// are merged into a single HTTP request. Fetching a few unwanted bytes is
+/// much cheaper than the round-trip time of setting up a new stream.
+const MERGE_THRESHOLD: u64 = 256 * 1024;
+
+/// The largest possible extra field in a local file header.
+const MAX_EXTRA_FIELD: u64 = u16::MAX as u64;
+
+/// The largest possible data descriptor following the file data.
+const MAX_DATA_DESCRIPTOR: u64 = 24;
+
+/// Work out which byte ranges of a zip file need to be fetched to extract
+/// the given entries. Each entry needs its local header and its data; we
+/// don't know the size of the extra field in the local header until we read
+/// it, so we assume each entry extends as far as the next entry (or the
+/// central directory), but no further than the largest size it could
+/// possibly be. Sizes come from the zip file, so may be absurd; no span
+/// extends past the start of the central directory. The returned ranges are sorted and non-overlapping, and
+/// nearby ranges are merged.
+pub(crate) fn plan_ranges(
+    central_directory: &CentralDirectory,
+    selected: &[usize],
+) -> Vec<Range<u64>> {
+    let mut header_starts: Vec<u64> = central_directory
+        .entries
+        .iter()
+        .map(|entry| entry.header_start)
+        .collect();
+    header_starts.sort_unstable();
+    header_starts.dedup();
+
+    let mut spans: Vec<Range<u64>> = selected
+        .iter()
+        .map(|&i| {
+            let entry = &central_directory.entries[i];
+            let start = entry.header_start;
+            let next_header = header_starts
+                .get(header_starts.partition_point(|&pos| pos <= start))
+                .copied()
+                .unwrap_or(central_directory.start);
+            let max_len = (LOCAL_FILE_HEADER_SIZE + entry.name_len + MAX_EXTRA_FIELD)
+                .saturating_add(entry.compressed_size)
+                .saturating_add(MAX_DATA_DESCRIPTOR);
+            let end = min(next_header, start.saturating_add(max_len));
+            start..min(end, central_directory.start)
+        })
+        .filter(|span| !span.is_empty())
+        .collect();
+    spans.sort_unstable_by_key(|span| span.start);
+
+    let mut merged: Vec<Range<u64>> = Vec::new();
+    for span in spans {
+        match merged.last_mut() {
+            Some(last) if span.start <= last.end.saturating_add(MERGE_THRESHOLD) => {
+                last.end = last.end.max(span.end);
+            }
+            _ => merged.push(span),
+        }
+    }
+    merged
+}
+
+/// Work out which byte ranges of a zip file hold the fixed-size part of
+/// each entry's local header, which is all we need to find where its data
+/// starts. Unlike [`plan_ranges`], nearby ranges aren't merged, since
+/// we'd rather make many small requests than download the whole file.
+pub(crate) fn plan_local_header_ranges(central_directory: &CentralDirectory) -> Vec<Range<u64>> {
+    let mut spans: Vec<Range<u64>> = central_directory
+        .entries
+        .iter()
+        .map(|entry| {
+            let start = entry.header_start;
+            start..start.saturating_add(LOCAL_FILE_HEADER_SIZE)
+        })
+        .collect();
+    spans.sort_unstable_by_key(|span| span.start);
+
+    let mut merged: Vec<Range<u64>> = Vec::new();
+    for span in spans {
+        match merged.last_mut() {
+            Some(last) if span.start <= last.end => last.end = last.end.max(span.end),
+            _ => merged.push(span),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use test_log::test;
+
+    use super::{plan_local_header_ranges, plan_ranges, MERGE_THRESHOLD};
+    use crate::unzip::central_directory::{CentralDirectory, CentralDirectoryEntry};
+
+    fn entry(header_start: u64, compressed_size: u64) -> CentralDirectoryEntry {
+        CentralDirectoryEntry {
+            name: "a".into(),
+            name_len: 1,
+            header_start,
+            compressed_size,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_plan_ranges() {
+        let far = 10 * MERGE_THRESHOLD;
+        let central_directory = CentralDirectory {
+            entries: vec![
+                entry(0, 100),
+                entry(200, far),
+                entry(far, 100),
+                entry(far + 200, 100),
+                entry(3 * far, 100),
+            ],
+            start: 3 * far + 200,
+        };
+        assert_eq!(plan_ranges(&central_directory, &[]), vec![]);
+        assert_eq!(plan_ranges(&central_directory, &[1]), vec![200..far]);
+        // Adjacent entries are merged, distant ones are not.
+        assert_eq!(
+            plan_ranges(&central_directory, &[4, 0, 1, 2]),
+            vec![0..far + 200, 3 * far..3 * far + 200]
+        );
+    }
+
+    #[test]
+    fn test_plan_ranges_bounded_by_entry_size() {
+        let central_directory = CentralDirectory {
+            entries: vec![entry(0, 100)],
+            start: 1024 * 1024 * 1024,
+        };
+        let ranges = plan_ranges(&central_directory, &[0]);
+        assert_eq!(ranges.len(), 1);
+        assert!(ranges[0].end < 1024 * 1024);
+    }
+
+    #[test]
+    fn test_plan_ranges_absurd_sizes() {
+        let central_directory = CentralDirectory {
+            entries: vec![
+                entry(0, u64::MAX - 10),
+                entry(u64::MAX - 10, u64::MAX),
+                entry(1000, 100),
+            ],
+            start: 2000,
+        };
+        assert_eq!(plan_ranges(&central_directory, &[0, 1, 2]), vec![0..2000]);
+        assert_eq!(plan_ranges(&central_directory, &[1]), vec![]);
+    }
+
+    #[test]
+    fn test_plan_local_header_ranges() {
+        let central_directory = CentralDirectory {
+            entries: vec![entry(1000, 100), entry(0, 100), entry(10, 100)],
+            start: 2000,
+        };
+        assert_eq!(
+            plan_local_header_ranges(&central_directory),
+            vec![0..40, 1000..1030]
+        );
+    }
+}
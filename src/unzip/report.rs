@@ -0,0 +1,136 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::fmt;
+
+use anyhow::Result;
+use thiserror::Error;
+
+use super::error::classify;
+
+/// What to do once a file fails to extract.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FailureMode {
+    /// Carry on extracting the other files, and report every failure.
+    #[default]
+    KeepGoing,
+    /// Stop as soon as possible, reporting only the first failure. Files
+    /// which are part-way through extraction are abandoned, and for a
+    /// URI, we stop downloading.
+    FailFast,
+}
+
+impl FailureMode {
+    /// Whether we should stop, given the failures so far.
+    pub(crate) fn should_stop(self, failures: &[EntryFailure]) -> bool {
+        self == Self::FailFast && !failures.is_empty()
+    }
+}
+
+/// Broadly, why a file couldn't be extracted. These correspond to the
+/// variants of [`super::Error`], and likewise more may be added in future.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FailureKind {
+    /// Fetching the zip file over HTTP failed.
+    Transport,
+    /// The file's data or headers are corrupt.
+    Archive,
+    /// The file uses a feature which isn't supported, such as encryption
+    /// or an unusual compression method.
+    Unsupported,
+    /// Reading or writing local files failed, for example because of
+    /// permissions or a full disk.
+    Filesystem(std::io::ErrorKind),
+    /// Extracting the file would have been unsafe.
+    Security,
+    /// One of the [`super::ExtractionLimits`] was exceeded.
+    Limit,
+    /// The extraction was cancelled before the file was finished.
+    Cancelled,
+    /// Anything else.
+    Other,
+}
+
+/// A file which couldn't be extracted (or, when testing, is corrupt).
+#[derive(Debug)]
+pub struct EntryFailure {
+    /// The name of the file within the zip file, or for failures which
+    /// concern an output directory, its path.
+    pub name: String,
+    /// The index of the file in the central directory, if known. It isn't
+    /// known for files extracted from a stream.
+    pub index: Option<usize>,
+    /// Broadly, what went wrong.
+    pub kind: FailureKind,
+    /// Exactly what went wrong.
+    pub error: anyhow::Error,
+}
+
+impl EntryFailure {
+    pub(crate) fn new(name: impl Into<String>, index: Option<usize>, error: anyhow::Error) -> Self {
+        Self {
+            name: name.into(),
+            index,
+            kind: classify(&error),
+            error,
+        }
+    }
+}
+
+/// The error returned when one or more files couldn't be extracted, listing
+/// every one of them.
+#[derive(Debug, Error)]
+pub struct EntryFailures {
+    /// The files which failed, in central directory order where possible.
+    pub failures: Vec<EntryFailure>,
+}
+
+impl EntryFailures {
+    /// Succeed if nothing failed, or else return an [`EntryFailures`].
+    pub(crate) fn into_result(failures: Vec<EntryFailure>) -> Result<()> {
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(Self { failures }.into())
+        }
+    }
+}
+
+impl fmt::Display for EntryFailures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.failures.as_slice() {
+            [] => write!(f, "No files failed"),
+            [failure] => write!(f, "{:#}", failure.error),
+            [failure, rest @ ..] => write!(
+                f,
+                "{:#} (and {} more files failed)",
+                failure.error,
+                rest.len()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_log::test;
+
+    use super::{EntryFailure, EntryFailures};
+
+    #[test]
+    fn test_display() {
+        let failures = EntryFailures {
+            failures: vec![
+                EntryFailure::new("a.txt", Some(0), anyhow::anyhow!("A")),
+                EntryFailure::new("b.txt", Some(1), anyhow::anyhow!("B")),
+            ],
+        };
+        assert_eq!(failures.to_string(), "A (and 1 more files failed)");
+    }
+}
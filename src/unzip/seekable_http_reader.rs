@@ -10,7 +10,11 @@ use std::{
     cmp::min,
     collections::BTreeMap,
     io::{BufReader, ErrorKind, Read, Seek, SeekFrom},
-    sync::{Arc, Condvar, Mutex},
+    ops::Range,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
 };
 
 use reqwest::blocking::Response;
@@ -35,9 +39,10 @@ use super::{
 const MAX_BLOCK: usize = 1024 * 1024;
 
 /// A hint to the [`SeekableHttpReaderEngine`] about the expected access pattern.
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
 pub(crate) enum AccessPattern {
     /// We expect accesses all over the file.
+    #[default]
     RandomAccess,
     /// We expect accesses starting from the beginning and moving to the end,
     /// though there might be some jumping around if multiple threads are
@@ -45,12 +50,6 @@ pub(crate) enum AccessPattern {
     SequentialIsh,
 }
 
-impl Default for AccessPattern {
-    fn default() -> Self {
-        Self::RandomAccess
-    }
-}
-
 /// Errors that may be returned by a [`SeekableHttpReaderEngine` or `SeekableHttpReader`].
 #[derive(Error, Debug)]
 pub(crate) enum Error {
@@ -62,6 +61,11 @@ pub(crate) enum Error {
     RangeFetcherError(http_range_reader::Error),
 }
 
+/// The error with which reads fail once we've aborted.
+#[derive(Error, Debug)]
+#[error("Download aborted")]
+pub(crate) struct Aborted;
+
 /// Some data that we've read from the network, but not yet returned to the
 /// caller.
 struct CacheCell {
@@ -204,11 +208,56 @@ impl std::fmt::Debug for State {
     }
 }
 
+/// An HTTP stream from which we're reading a particular range of the
+/// resource.
+struct CurrentReader {
+    reader: BufReader<Response>,
+    /// Current position of the reader within the resource.
+    pos: u64,
+    /// The end of the range this stream was asked to return.
+    end: u64,
+}
+
 /// Items related to reading from the underlying HTTP streams. This is
 /// in a separate struct because it's protected by a mutex.
 struct ReadingMaterials {
     range_fetcher: RangeFetcher,
-    reader: Option<(BufReader<Response>, u64)>, // second item in tuple is current reader pos
+    reader: Option<CurrentReader>,
+    /// The ranges of the resource which we expect to need, sorted and
+    /// non-overlapping. If empty, we assume we need everything.
+    planned_ranges: Vec<Range<u64>>,
+}
+
+impl ReadingMaterials {
+    /// Work out which range to request from the server, given that we've
+    /// already got everything we need before `start`, and that we
+    /// specifically need to read from `pos`. Where we have a plan, we
+    /// skip the gaps between the planned ranges.
+    fn next_range(&self, start: u64, pos: u64) -> Range<u64> {
+        let len = self.range_fetcher.len();
+        let next_planned = self.planned_ranges.iter().find(|range| range.end > start);
+        match next_planned {
+            None => start..len,
+            Some(range) => min(pos, range.start).max(start)..range.end,
+        }
+    }
+
+    /// Create a new HTTP stream, replacing any existing one. See
+    /// [`Self::next_range`] for the meaning of the parameters.
+    fn create_reader(&mut self, start: u64, pos: u64) -> std::io::Result<()> {
+        let range = self.next_range(start, pos);
+        log::info!("create_reader 0x{:x}-0x{:x}", range.start, range.end);
+        let reader = self
+            .range_fetcher
+            .fetch_range(range.clone())
+            .map_err(|e| std::io::Error::new(ErrorKind::Unsupported, e))?;
+        self.reader = Some(CurrentReader {
+            reader: BufReader::new(reader),
+            pos: range.start,
+            end: range.end,
+        });
+        Ok(())
+    }
 }
 
 /// A type which can produce objects that can be [`Read`] and [`Seek`] even
@@ -230,6 +279,8 @@ pub(crate) struct SeekableHttpReaderEngine {
     /// readahead cache and all other threads should consider if their read
     /// request can be serviced.
     read_completed: Condvar,
+    /// Set by [`Self::abort`], after which all reads fail.
+    aborted: AtomicBool,
 }
 
 /// Some results about the success (or otherwise) of this reader.
@@ -266,9 +317,11 @@ impl SeekableHttpReaderEngine {
             reader: Mutex::new(ReadingMaterials {
                 range_fetcher,
                 reader: None,
+                planned_ranges: Vec::new(),
             }),
             state: Mutex::new(State::new(readahead_limit, access_pattern)),
             read_completed: Condvar::new(),
+            aborted: AtomicBool::new(false),
         }))
     }
 
@@ -329,6 +382,9 @@ impl SeekableHttpReaderEngine {
 
         // Claim CACHE mutex
         let mut state = self.state.lock().unwrap();
+        if self.aborted.load(Ordering::Relaxed) {
+            return Err(aborted_error());
+        }
         // Is there block in cache?
         // - If yes, release CACHE mutex, and return
         if let Some(bytes_read_from_cache) = state.read_from_cache(pos, buf) {
@@ -341,6 +397,9 @@ impl SeekableHttpReaderEngine {
         while read_in_progress {
             //   - If yes, release CACHE mutex, WAIT on condvar atomically
             state = self.read_completed.wait(state).unwrap();
+            if self.aborted.load(Ordering::Relaxed) {
+                return Err(aborted_error());
+            }
             //     check cache again
             if let Some(bytes_read_from_cache) = state.read_from_cache(pos, buf) {
                 log::info!("Deferred cache success");
@@ -358,47 +417,58 @@ impl SeekableHttpReaderEngine {
         drop(state);
         //     perform read
         // First check if we need to rewind.
-        if let Some((_, readerpos)) = reading_stuff.reader.as_ref() {
-            if pos < *readerpos {
+        if let Some(current) = reading_stuff.reader.as_ref() {
+            if pos < current.pos {
                 log::info!(
                     "New reader will be required at 0x{:x} - old reader pos was 0x{:x}",
                     pos,
-                    *readerpos
+                    current.pos
                 );
                 reading_stuff.reader = None;
             }
         }
-        let mut reader_created = false;
+        let mut readers_created = 0;
         if reading_stuff.reader.is_none() {
-            log::info!("create_reader");
-            reading_stuff.reader = Some((
-                BufReader::new(
-                    reading_stuff
-                        .range_fetcher
-                        .fetch_range(pos)
-                        .map_err(|e| std::io::Error::new(ErrorKind::Unsupported, e.to_string()))?,
-                ),
-                pos,
-            ));
-            reader_created = true;
+            reading_stuff.create_reader(pos, pos)?;
+            readers_created += 1;
         };
 
-        let (reader, reader_pos) = reading_stuff.reader.as_mut().unwrap();
-        if pos > *reader_pos {
-            log::info!("Read: fast-forward from 0x{:x} to 0x{:x}", *reader_pos, pos);
+        if pos > reading_stuff.reader.as_ref().unwrap().pos {
+            log::info!(
+                "Read: fast-forward from 0x{:x} to 0x{:x}",
+                reading_stuff.reader.as_ref().unwrap().pos,
+                pos
+            );
         }
-        while pos >= *reader_pos {
+        loop {
+            if self.aborted.load(Ordering::Relaxed) {
+                // Close the HTTP stream rather than downloading any more.
+                reading_stuff.reader = None;
+                return Err(aborted_error());
+            }
+            let current = reading_stuff.reader.as_mut().unwrap();
+            if pos < current.pos {
+                break;
+            }
+            if current.pos == current.end {
+                // We've read everything this stream was asked for, so move
+                // on to the next range.
+                let end = current.end;
+                reading_stuff.create_reader(end, pos)?;
+                readers_created += 1;
+                continue;
+            }
             // Fast forward beyond the desired position, recording any reads in the cache
             // for later.
-            let to_read = min(MAX_BLOCK, self.len as usize - *reader_pos as usize);
+            let to_read = min(MAX_BLOCK as u64, current.end - current.pos) as usize;
             let mut new_block = vec![0u8; to_read];
-            reader.read_exact(&mut new_block)?;
+            current.reader.read_exact(&mut new_block)?;
             //     claim STATE mutex
             let mut state = self.state.lock().unwrap();
-            state.insert(*reader_pos, new_block);
+            state.insert(current.pos, new_block);
             // Tell any waiting threads they should re-check the cache
             self.read_completed.notify_all();
-            *reader_pos += to_read as u64;
+            current.pos += to_read as u64;
         }
         // Because the above condition is >=, and because we know the request was not
         // to read at the very end of the file, we know we now have some data in the
@@ -409,9 +479,7 @@ impl SeekableHttpReaderEngine {
             .read_from_cache(pos, buf)
             .expect("Cache still couldn't satisfy request event after reading beyond read pos");
         log::info!("Cache success after read");
-        if reader_created {
-            state.stats.num_http_streams += 1;
-        }
+        state.stats.num_http_streams += readers_created;
         //     set read not in progress
         state.read_in_progress = false;
         //     release STATE mutex
@@ -424,6 +492,16 @@ impl SeekableHttpReaderEngine {
         self.len
     }
 
+    /// Tell the engine which ranges of the resource we will need to read,
+    /// so that it avoids fetching anything else. The ranges must be sorted
+    /// and non-overlapping. Reads outside these ranges will still work, but
+    /// may be slow. Call this before switching to
+    /// [`AccessPattern::SequentialIsh`].
+    pub(crate) fn set_planned_ranges(&self, planned_ranges: Vec<Range<u64>>) {
+        log::info!("Planned ranges: {:x?}", planned_ranges);
+        self.reader.lock().unwrap().planned_ranges = planned_ranges;
+    }
+
     /// Update the expected access pattern. You must not call this when
     /// any threads might be reading from any [`SeekableHttpReader`] created
     /// by this engine; that may panic.
@@ -442,13 +520,17 @@ impl SeekableHttpReaderEngine {
                 panic!("Must not call set_expected_access_pattern while a read is in progress");
             }
             // If we're switching to a sequential pattern, recreate
-            // the reader at position zero.
+            // the reader at position zero (or wherever the first planned
+            // range begins).
             log::info!("create_reader_at_zero");
             {
                 let mut reading_materials = self.reader.lock().unwrap();
-                let new_reader = reading_materials.range_fetcher.fetch_range(0);
-                if let Ok(new_reader) = new_reader {
-                    reading_materials.reader = Some((BufReader::new(new_reader), 0));
+                let start = reading_materials
+                    .planned_ranges
+                    .first()
+                    .map_or(0, |range| range.start);
+                if let Err(e) = reading_materials.create_reader(start, start) {
+                    log::info!("Unable to create reader: {}", e);
                 }
             }
             state.stats.num_http_streams += 1;
@@ -456,6 +538,21 @@ impl SeekableHttpReaderEngine {
         state.access_pattern = access_pattern;
     }
 
+    /// Stop downloading. Any HTTP stream is closed, and all current and
+    /// future reads fail.
+    pub(crate) fn abort(&self) {
+        log::info!("Aborting HTTP reads");
+        let _state = self.state.lock().unwrap();
+        self.aborted.store(true, Ordering::Relaxed);
+        // Wake any threads waiting for a read, so they see we've aborted.
+        self.read_completed.notify_all();
+        // If a read is in progress, the thread doing it will close the
+        // stream itself.
+        if let Ok(mut reading_materials) = self.reader.try_lock() {
+            reading_materials.reader = None;
+        }
+    }
+
     /// Return some statistics about the success (or otherwise) of this stream.
     pub(crate) fn get_stats(&self) -> SeekableHttpReaderStatistics {
         self.state.lock().unwrap().stats.clone()
@@ -468,6 +565,10 @@ impl Drop for SeekableHttpReaderEngine {
     }
 }
 
+fn aborted_error() -> std::io::Error {
+    std::io::Error::other(Aborted)
+}
+
 /// A [`Read`] which is also [`Seek`] to read from arbitrary places on an
 /// HTTP stream. Cheap to clone. Create using [`SeekableHttpReader::create_reader`].
 #[derive(Clone)]
@@ -518,12 +619,18 @@ impl HasLength for SeekableHttpReader {
 
 #[cfg(test)]
 mod tests {
-    use std::io::{Read, Seek, SeekFrom};
+    use std::{
+        io::{Read, Seek, SeekFrom},
+        sync::Arc,
+        thread,
+        time::{Duration, Instant},
+    };
     use test_log::test;
 
     use httptest::{matchers::*, responders::*, Expectation, Server};
 
     use super::{AccessPattern, SeekableHttpReaderEngine};
+    use crate::CancellationToken;
 
     #[test]
     fn test_unlimited_readahead() {
@@ -604,4 +711,129 @@ mod tests {
         seekable_http_reader.read_exact(&mut throwaway).unwrap();
         assert_eq!(std::str::from_utf8(&throwaway).unwrap(), "4567");
     }
+
+    #[test]
+    fn test_cancel_while_waiting_for_read() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("HEAD", "/foo")).respond_with(
+                status_code(200)
+                    .insert_header("Accept-Ranges", "bytes")
+                    .insert_header("Content-Length", "12")
+                    .body("0123456789AB"),
+            ),
+        );
+        let engine = SeekableHttpReaderEngine::new(
+            server.url("/foo").to_string(),
+            None,
+            AccessPattern::SequentialIsh,
+        )
+        .unwrap();
+        let token = CancellationToken::new();
+        let weak_engine = Arc::downgrade(&engine);
+        let _registration = token.on_cancel(move || {
+            if let Some(engine) = weak_engine.upgrade() {
+                engine.abort();
+            }
+        });
+        // The server is slow, so the first reader is stuck waiting for it,
+        // and the second for the first.
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/foo"))
+                .times(..)
+                .respond_with(delay_and_then(
+                    Duration::from_secs(3),
+                    status_code(200)
+                        .insert_header("Accept-Ranges", "bytes")
+                        .insert_header("Content-Length", "12")
+                        .body("0123456789AB"),
+                )),
+        );
+        let read = |engine: &Arc<SeekableHttpReaderEngine>| {
+            let mut reader = engine.clone().create_reader();
+            thread::spawn(move || reader.read_exact(&mut [0u8; 4]))
+        };
+        let first = read(&engine);
+        thread::sleep(Duration::from_millis(300));
+        let second = read(&engine);
+        thread::sleep(Duration::from_millis(300));
+        let cancelled_at = Instant::now();
+        token.cancel();
+        let error = second.join().unwrap().unwrap_err();
+        assert!(cancelled_at.elapsed() < Duration::from_secs(2));
+        assert_eq!(error.to_string(), "Download aborted");
+        // The first reader notices once the server responds.
+        assert!(first.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_planned_ranges() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("HEAD", "/foo")).respond_with(
+                status_code(200)
+                    .insert_header("Accept-Ranges", "bytes")
+                    .insert_header("Content-Length", "12")
+                    .body("0123456789AB"),
+            ),
+        );
+        let engine = SeekableHttpReaderEngine::new(
+            server.url("/foo").to_string(),
+            None,
+            AccessPattern::RandomAccess,
+        )
+        .unwrap();
+        // Only the planned ranges should ever be requested.
+        for (range, body) in [("bytes=2-3", "23"), ("bytes=8-9", "89")] {
+            server.expect(
+                Expectation::matching(all_of![
+                    request::method_path("GET", "/foo"),
+                    request::headers(contains(("range", range))),
+                ])
+                .respond_with(status_code(206).body(body)),
+            );
+        }
+        engine.set_planned_ranges(vec![2..4, 8..10]);
+        engine.set_expected_access_pattern(AccessPattern::SequentialIsh);
+        let mut seekable_http_reader = engine.clone().create_reader();
+        let mut throwaway = [0u8; 2];
+        seekable_http_reader.seek(SeekFrom::Start(2)).unwrap();
+        seekable_http_reader.read_exact(&mut throwaway).unwrap();
+        assert_eq!(std::str::from_utf8(&throwaway).unwrap(), "23");
+        seekable_http_reader.seek(SeekFrom::Start(8)).unwrap();
+        seekable_http_reader.read_exact(&mut throwaway).unwrap();
+        assert_eq!(std::str::from_utf8(&throwaway).unwrap(), "89");
+        assert_eq!(engine.get_stats().num_http_streams, 2);
+    }
+
+    #[test]
+    fn test_abort() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("HEAD", "/foo")).respond_with(
+                status_code(200)
+                    .insert_header("Accept-Ranges", "bytes")
+                    .insert_header("Content-Length", "12")
+                    .body("0123456789AB"),
+            ),
+        );
+        let engine = SeekableHttpReaderEngine::new(
+            server.url("/foo").to_string(),
+            None,
+            AccessPattern::RandomAccess,
+        )
+        .unwrap();
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/foo"))
+                .respond_with(status_code(206).body("0123456789AB")),
+        );
+        let mut seekable_http_reader = engine.clone().create_reader();
+        let mut throwaway = [0u8; 4];
+        seekable_http_reader.read_exact(&mut throwaway).unwrap();
+        engine.abort();
+        // Even data we've already downloaded is no longer returned.
+        assert!(seekable_http_reader.read_exact(&mut throwaway).is_err());
+        seekable_http_reader.rewind().unwrap();
+        assert!(seekable_http_reader.read_exact(&mut throwaway).is_err());
+    }
 }
@@ -0,0 +1,490 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use filetime::FileTime;
+
+use super::{
+    free_space::check_free_space,
+    symlinks::{check_no_symlinks_in_path, create_symlink, remove_existing_symlink},
+    Error, OverwritePolicy,
+};
+
+/// Somewhere to put the files, directories and symlinks we extract, such as
+/// a directory on the filesystem ([`FilesystemSink`]) or memory
+/// ([`MemorySink`]).
+///
+/// Paths are relative to the root of the extraction, and have already been
+/// checked not to escape it. Methods are called from many threads at once.
+/// Errors are reported as an [`Error`], into which an [`std::io::Error`]
+/// converts; they're reported against the file being extracted.
+pub trait ExtractionSink: Sync {
+    /// Create a directory, along with any missing parents. An empty path
+    /// refers to the root of the extraction.
+    fn create_dir_all(&self, path: &Path) -> Result<(), Error>;
+
+    /// Open a file for writing, replacing anything already there, or return
+    /// `None` to skip it, for example because it already exists. Its parent
+    /// directory has already been created.
+    fn create_file(
+        &self,
+        path: &Path,
+        metadata: &FileMetadata,
+    ) -> Result<Option<Box<dyn Write + '_>>, Error>;
+
+    /// Apply the permissions and modification time of a file once it has
+    /// been written, or of a directory once every file has been written.
+    fn set_metadata(&self, path: &Path, metadata: &FileMetadata) -> Result<(), Error>;
+
+    /// Create a symlink pointing at `target`, which has been checked to stay
+    /// within the extraction. Symlinks are created once every file has been
+    /// written.
+    fn create_symlink(
+        &self,
+        path: &Path,
+        target: &Path,
+        metadata: &FileMetadata,
+    ) -> Result<(), Error>;
+
+    /// Remove a file, either because we couldn't finish writing it, or to
+    /// replace it with a symlink.
+    fn remove_file(&self, path: &Path) -> Result<(), Error>;
+
+    /// Fail if there isn't room for `needed` bytes of files. By default,
+    /// there's always room.
+    fn check_free_space(&self, _needed: u64) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// What we know about a file, directory or symlink we're extracting.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileMetadata {
+    /// Unix permissions, including the file type, if the zip file records
+    /// them.
+    pub unix_mode: Option<u32>,
+    /// The modification time, if the zip file records it.
+    pub mtime: Option<SystemTime>,
+}
+
+/// An [`ExtractionSink`] which writes into a directory on the filesystem.
+/// It refuses to write through symlinks, even those it created itself.
+pub struct FilesystemSink {
+    root: PathBuf,
+    overwrite_policy: OverwritePolicy,
+    /// Ensures we don't conflict in creating directories between threads.
+    lock: Mutex<()>,
+}
+
+impl FilesystemSink {
+    /// Create a sink which writes into `root`, creating it if needed, and
+    /// which deals with existing files according to `overwrite_policy`.
+    pub fn new(root: impl Into<PathBuf>, overwrite_policy: OverwritePolicy) -> Self {
+        Self {
+            root: root.into(),
+            overwrite_policy,
+            lock: Mutex::new(()),
+        }
+    }
+}
+
+impl ExtractionSink for FilesystemSink {
+    fn create_dir_all(&self, path: &Path) -> Result<(), Error> {
+        check_no_symlinks_in_path(&self.root, path)?;
+        let path = self.root.join(path);
+        // Fast path - avoid locking if the directory exists
+        if path.exists() {
+            return Ok(());
+        }
+        let _exclusivity = self.lock.lock().unwrap();
+        if path.exists() {
+            return Ok(());
+        }
+        std::fs::create_dir_all(path)
+            .with_context(|| "Failed to create directory")
+            .map_err(Error::from)
+    }
+
+    fn create_file(
+        &self,
+        path: &Path,
+        metadata: &FileMetadata,
+    ) -> Result<Option<Box<dyn Write + '_>>, Error> {
+        let out_path = self.root.join(path);
+        if !self
+            .overwrite_policy
+            .should_write(&out_path, || metadata.mtime)?
+        {
+            return Ok(None);
+        }
+        let file = if self.overwrite_policy.replaces_existing() {
+            remove_existing_symlink(&out_path)?;
+            File::create(&out_path)
+        } else {
+            match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&out_path)
+            {
+                Err(e)
+                    if e.kind() == ErrorKind::AlreadyExists
+                        && self.overwrite_policy.skips_existing() =>
+                {
+                    log::info!("Skipping existing file {}", path.display());
+                    return Ok(None);
+                }
+                result => result,
+            }
+        }
+        .with_context(|| "Failed to create file")?;
+        Ok(Some(Box::new(file)))
+    }
+
+    fn set_metadata(&self, path: &Path, metadata: &FileMetadata) -> Result<(), Error> {
+        let out_path = self.root.join(path);
+        #[cfg(unix)]
+        if let Some(mode) = metadata.unix_mode {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&out_path, std::fs::Permissions::from_mode(mode))
+                .with_context(|| "Failed to set permissions")?;
+        }
+        if let Some(mtime) = metadata.mtime {
+            filetime::set_file_mtime(&out_path, FileTime::from_system_time(mtime))
+                .with_context(|| "Failed to set modification time")?;
+        }
+        Ok(())
+    }
+
+    fn create_symlink(
+        &self,
+        path: &Path,
+        target: &Path,
+        metadata: &FileMetadata,
+    ) -> Result<(), Error> {
+        create_symlink(
+            &self.root,
+            path,
+            target,
+            metadata.mtime,
+            self.overwrite_policy,
+        )
+        .map_err(Error::from)
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<(), Error> {
+        std::fs::remove_file(self.root.join(path))
+            .with_context(|| "Failed to remove file")
+            .map_err(Error::from)
+    }
+
+    fn check_free_space(&self, needed: u64) -> Result<(), Error> {
+        check_free_space(&self.root, needed).map_err(Error::from)
+    }
+}
+
+/// Something extracted into a [`MemorySink`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemoryEntry {
+    /// A regular file, with its contents.
+    File {
+        data: Vec<u8>,
+        metadata: FileMetadata,
+    },
+    /// A directory.
+    Directory { metadata: FileMetadata },
+    /// A symlink, with its target.
+    Symlink {
+        target: PathBuf,
+        metadata: FileMetadata,
+    },
+}
+
+/// An [`ExtractionSink`] which keeps everything in memory, for example for
+/// tests.
+#[derive(Debug, Default)]
+pub struct MemorySink {
+    entries: Mutex<BTreeMap<PathBuf, MemoryEntry>>,
+}
+
+impl MemorySink {
+    /// Create an empty sink.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Everything extracted, by path.
+    pub fn into_entries(self) -> BTreeMap<PathBuf, MemoryEntry> {
+        self.entries.into_inner().unwrap()
+    }
+}
+
+impl ExtractionSink for MemorySink {
+    fn create_dir_all(&self, path: &Path) -> Result<(), Error> {
+        let mut entries = self.entries.lock().unwrap();
+        let dirs: Vec<_> = path
+            .ancestors()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .collect();
+        // Start at the top, so we fail before creating anything beneath a
+        // file.
+        for dir in dirs.into_iter().rev() {
+            match entries.get(dir) {
+                Some(MemoryEntry::Directory { .. }) => {}
+                Some(_) => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::AlreadyExists,
+                        format!("{} already exists and isn't a directory", dir.display()),
+                    ))
+                    .with_context(|| "Failed to create directory")
+                    .map_err(Error::from)
+                }
+                None => {
+                    entries.insert(
+                        dir.to_path_buf(),
+                        MemoryEntry::Directory {
+                            metadata: FileMetadata::default(),
+                        },
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn create_file(
+        &self,
+        path: &Path,
+        _metadata: &FileMetadata,
+    ) -> Result<Option<Box<dyn Write + '_>>, Error> {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(MemoryEntry::Directory { .. }) = entries.get(path) {
+            return Err(std::io::Error::new(
+                ErrorKind::AlreadyExists,
+                "A directory already exists",
+            ))
+            .with_context(|| "Failed to create file")
+            .map_err(Error::from);
+        }
+        entries.insert(
+            path.to_path_buf(),
+            MemoryEntry::File {
+                data: Vec::new(),
+                metadata: FileMetadata::default(),
+            },
+        );
+        Ok(Some(Box::new(MemoryFile {
+            sink: self,
+            path: path.to_path_buf(),
+            data: Vec::new(),
+        })))
+    }
+
+    fn set_metadata(&self, path: &Path, metadata: &FileMetadata) -> Result<(), Error> {
+        match self.entries.lock().unwrap().get_mut(path) {
+            Some(
+                MemoryEntry::File {
+                    metadata: existing, ..
+                }
+                | MemoryEntry::Directory { metadata: existing }
+                | MemoryEntry::Symlink {
+                    metadata: existing, ..
+                },
+            ) => {
+                *existing = metadata.clone();
+                Ok(())
+            }
+            None => Err(std::io::Error::from(ErrorKind::NotFound))
+                .with_context(|| "Failed to set metadata")
+                .map_err(Error::from),
+        }
+    }
+
+    fn create_symlink(
+        &self,
+        path: &Path,
+        target: &Path,
+        metadata: &FileMetadata,
+    ) -> Result<(), Error> {
+        self.entries.lock().unwrap().insert(
+            path.to_path_buf(),
+            MemoryEntry::Symlink {
+                target: target.to_path_buf(),
+                metadata: metadata.clone(),
+            },
+        );
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<(), Error> {
+        self.entries.lock().unwrap().remove(path);
+        Ok(())
+    }
+}
+
+/// Everything extracted by [`super::UnzipEngine::extract_to_memory`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtractedFiles {
+    /// The contents of each regular file, by path.
+    pub files: BTreeMap<PathBuf, Vec<u8>>,
+    /// The target of each symlink, by path.
+    pub symlinks: BTreeMap<PathBuf, PathBuf>,
+    /// The metadata of every file, directory and symlink, by path.
+    pub metadata: BTreeMap<PathBuf, FileMetadata>,
+}
+
+impl From<MemorySink> for ExtractedFiles {
+    fn from(sink: MemorySink) -> Self {
+        let mut extracted = Self::default();
+        for (path, entry) in sink.into_entries() {
+            let metadata = match entry {
+                MemoryEntry::File { data, metadata } => {
+                    extracted.files.insert(path.clone(), data);
+                    metadata
+                }
+                MemoryEntry::Directory { metadata } => metadata,
+                MemoryEntry::Symlink { target, metadata } => {
+                    extracted.symlinks.insert(path.clone(), target);
+                    metadata
+                }
+            };
+            extracted.metadata.insert(path, metadata);
+        }
+        extracted
+    }
+}
+
+/// A file being written into a [`MemorySink`]. Its data is stored once
+/// it's dropped.
+struct MemoryFile<'a> {
+    sink: &'a MemorySink,
+    path: PathBuf,
+    data: Vec<u8>,
+}
+
+impl Write for MemoryFile<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.data.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for MemoryFile<'_> {
+    fn drop(&mut self) {
+        if let Some(MemoryEntry::File { data, .. }) =
+            self.sink.entries.lock().unwrap().get_mut(&self.path)
+        {
+            *data = std::mem::take(&mut self.data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, path::Path};
+    use tempfile::tempdir;
+    use test_log::test;
+
+    use super::{ExtractionSink, FileMetadata, FilesystemSink, MemoryEntry, MemorySink};
+    use crate::OverwritePolicy;
+
+    #[test]
+    fn test_filesystem_sink_refuses_existing() {
+        let td = tempdir().unwrap();
+        let sink = FilesystemSink::new(td.path(), OverwritePolicy::Error);
+        let metadata = FileMetadata::default();
+        let existing = td.path().join("existing");
+        std::fs::write(&existing, "Local edits").unwrap();
+        assert!(sink.create_file(Path::new("existing"), &metadata).is_err());
+        assert_eq!(std::fs::read_to_string(&existing).unwrap(), "Local edits");
+        // A symlink is refused too, rather than followed.
+        #[cfg(unix)]
+        {
+            let target = td.path().join("target");
+            std::os::unix::fs::symlink(&target, td.path().join("link")).unwrap();
+            assert!(sink.create_file(Path::new("link"), &metadata).is_err());
+            assert!(!target.exists());
+        }
+        let mut file = sink
+            .create_file(Path::new("new"), &metadata)
+            .unwrap()
+            .unwrap();
+        file.write_all(b"New").unwrap();
+        drop(file);
+        assert_eq!(
+            std::fs::read_to_string(td.path().join("new")).unwrap(),
+            "New"
+        );
+    }
+
+    #[test]
+    fn test_filesystem_sink_skips_existing() {
+        let td = tempdir().unwrap();
+        let sink = FilesystemSink::new(td.path(), OverwritePolicy::Never);
+        let metadata = FileMetadata::default();
+        let existing = td.path().join("existing");
+        std::fs::write(&existing, "Local edits").unwrap();
+        assert!(sink
+            .create_file(Path::new("existing"), &metadata)
+            .unwrap()
+            .is_none());
+        assert_eq!(std::fs::read_to_string(&existing).unwrap(), "Local edits");
+        assert!(sink
+            .create_file(Path::new("new"), &metadata)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_memory_sink() {
+        let sink = MemorySink::new();
+        let metadata = FileMetadata {
+            unix_mode: Some(0o100644),
+            mtime: None,
+        };
+        sink.create_dir_all(Path::new("a/b")).unwrap();
+        let mut file = sink
+            .create_file(Path::new("a/b/c.txt"), &metadata)
+            .unwrap()
+            .unwrap();
+        file.write_all(b"Contents of C").unwrap();
+        drop(file);
+        sink.set_metadata(Path::new("a/b/c.txt"), &metadata)
+            .unwrap();
+        // Files and directories can't replace one another.
+        assert!(sink.create_dir_all(Path::new("a/b/c.txt/d")).is_err());
+        assert!(sink.create_file(Path::new("a/b"), &metadata).is_err());
+        sink.create_symlink(Path::new("a/link"), Path::new("b/c.txt"), &metadata)
+            .unwrap();
+        sink.remove_file(Path::new("a/link")).unwrap();
+        let entries = sink.into_entries();
+        assert_eq!(entries.len(), 3);
+        assert!(matches!(
+            entries[Path::new("a")],
+            MemoryEntry::Directory { .. }
+        ));
+        assert_eq!(
+            entries[Path::new("a/b/c.txt")],
+            MemoryEntry::File {
+                data: b"Contents of C".to_vec(),
+                metadata,
+            }
+        );
+    }
+}
@@ -0,0 +1,509 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::io::{BufRead, BufReader, Read, Write};
+
+use anyhow::{Context, Result};
+
+use super::{
+    central_directory::{
+        decode_name, extra_fields, le_u16, le_u32, le_u64, CENTRAL_DIRECTORY_END_SIGNATURE,
+        CENTRAL_DIRECTORY_HEADER_SIGNATURE, FLAG_ENCRYPTED, LOCAL_FILE_HEADER_SIZE,
+        ZIP64_BYTES_THR, ZIP64_CENTRAL_DIRECTORY_END_SIGNATURE, ZIP64_EXTRA_FIELD_TAG,
+    },
+    error::{fail, ClassifiedError},
+    FailureKind,
+};
+
+const LOCAL_FILE_HEADER_SIGNATURE: u32 = 0x04034b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x08074b50;
+
+/// Bit 3 of the general purpose flags indicates that the CRC and sizes
+/// are zero in the local header, and are instead in a data descriptor
+/// following the file data.
+const FLAG_DATA_DESCRIPTOR: u16 = 1 << 3;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+const METHOD_BZIP2: u16 = 12;
+const METHOD_ZSTD: u16 = 93;
+
+/// A local file header, which precedes the data of each file within a
+/// zip file.
+pub(crate) struct LocalFileHeader {
+    /// The file name, decoded the same way as `zip-rs` does.
+    pub(crate) name: String,
+    /// The file name as stored, before decoding.
+    pub(crate) raw_name: Vec<u8>,
+    pub(crate) flags: u16,
+    pub(crate) compression_method: u16,
+    pub(crate) last_modified: zip::DateTime,
+    /// The CRC and sizes are zero if there's a data descriptor.
+    pub(crate) crc32: u32,
+    pub(crate) compressed_size: u64,
+    pub(crate) uncompressed_size: u64,
+    pub(crate) extra: Vec<u8>,
+    /// Whether there's a zip64 extra field, in which case any data
+    /// descriptor has 64-bit sizes.
+    pub(crate) zip64: bool,
+    /// Offset of this header within the stream.
+    pub(crate) header_start: u64,
+    /// Offset of the file data within the stream.
+    pub(crate) data_start: u64,
+}
+
+impl LocalFileHeader {
+    pub(crate) fn has_data_descriptor(&self) -> bool {
+        self.flags & FLAG_DATA_DESCRIPTOR != 0
+    }
+}
+
+/// The CRC and sizes of a file, as found once we've read all its data.
+pub(crate) struct EntryTrailer {
+    pub(crate) crc32: u32,
+    pub(crate) compressed_size: u64,
+    pub(crate) uncompressed_size: u64,
+}
+
+/// Reads a zip file from a non-seekable stream by walking through the local
+/// file headers in order. The central directory is never consulted, so
+/// we know nothing about each file except what its local header says.
+pub(crate) struct ZipStreamReader<R: Read> {
+    reader: BufReader<CountingReader<R>>,
+}
+
+impl<R: Read> ZipStreamReader<R> {
+    pub(crate) fn new(reader: R) -> Self {
+        Self {
+            reader: BufReader::new(CountingReader {
+                inner: reader,
+                count: 0,
+            }),
+        }
+    }
+
+    /// How far through the stream we are.
+    pub(crate) fn position(&self) -> u64 {
+        self.reader.get_ref().count - self.reader.buffer().len() as u64
+    }
+
+    /// Read the next local file header, or return `None` if we've reached
+    /// the central directory.
+    pub(crate) fn next_header(&mut self) -> Result<Option<LocalFileHeader>> {
+        let header_start = self.position();
+        let mut fixed = [0u8; LOCAL_FILE_HEADER_SIZE as usize];
+        self.reader
+            .read_exact(&mut fixed[..4])
+            .with_context(|| "Failed to read local file header")?;
+        match le_u32(&fixed) {
+            LOCAL_FILE_HEADER_SIGNATURE => {}
+            CENTRAL_DIRECTORY_HEADER_SIGNATURE
+            | CENTRAL_DIRECTORY_END_SIGNATURE
+            | ZIP64_CENTRAL_DIRECTORY_END_SIGNATURE => return Ok(None),
+            _ => fail!(
+                Archive,
+                "Invalid local file header at offset {header_start}"
+            ),
+        }
+        self.reader
+            .read_exact(&mut fixed[4..])
+            .with_context(|| "Local file header is truncated")?;
+        let flags = le_u16(&fixed[6..]);
+        let name_len = le_u16(&fixed[26..]) as usize;
+        let extra_len = le_u16(&fixed[28..]) as usize;
+        let mut variable = vec![0u8; name_len + extra_len];
+        self.reader
+            .read_exact(&mut variable)
+            .with_context(|| "Local file header is truncated")?;
+        let extra = variable.split_off(name_len);
+        let mut compressed_size = le_u32(&fixed[18..]) as u64;
+        let mut uncompressed_size = le_u32(&fixed[22..]) as u64;
+        let mut zip64 = false;
+        for (tag, field) in extra_fields(&extra) {
+            if tag == ZIP64_EXTRA_FIELD_TAG {
+                zip64 = true;
+                // Unlike in the central directory, both sizes must be
+                // present in the local header.
+                if field.len() >= 16 {
+                    if uncompressed_size == ZIP64_BYTES_THR {
+                        uncompressed_size = le_u64(field);
+                    }
+                    if compressed_size == ZIP64_BYTES_THR {
+                        compressed_size = le_u64(&field[8..]);
+                    }
+                }
+            }
+        }
+        Ok(Some(LocalFileHeader {
+            name: decode_name(&variable, flags),
+            raw_name: variable,
+            flags,
+            compression_method: le_u16(&fixed[8..]),
+            last_modified: zip::DateTime::from_msdos(le_u16(&fixed[12..]), le_u16(&fixed[10..])),
+            crc32: le_u32(&fixed[14..]),
+            compressed_size,
+            uncompressed_size,
+            extra,
+            zip64,
+            header_start,
+            data_start: self.position(),
+        }))
+    }
+
+    /// Skip over the data of a file without extracting it.
+    pub(crate) fn skip_entry(&mut self, header: &LocalFileHeader) -> Result<()> {
+        if header.has_data_descriptor() {
+            // We can only find the end of the data by decompressing it.
+            // Whether it's valid doesn't matter, since we're not using it.
+            let _ = self.read_entry(header, |_| Ok(()))?;
+        } else {
+            let skipped = std::io::copy(
+                &mut (&mut self.reader).take(header.compressed_size),
+                &mut std::io::sink(),
+            )?;
+            if skipped != header.compressed_size {
+                fail!(Archive, "Unexpected end of stream within {}", header.name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the data of a file. `extract` is given a reader yielding the
+    /// decompressed data, and any data it doesn't read is then skipped. If
+    /// `extract` fails, the rest is only decompressed if there's a data
+    /// descriptor, since otherwise we know where the data ends.
+    /// Errors in the stream itself are returned in the outer `Result`, after
+    /// which nothing more can be read. Errors from `extract`, or because the
+    /// data didn't match its CRC, are returned in the inner `Result`.
+    pub(crate) fn read_entry(
+        &mut self,
+        header: &LocalFileHeader,
+        extract: impl FnOnce(&mut dyn Read) -> Result<()>,
+    ) -> Result<Result<EntryTrailer>> {
+        if header.flags & FLAG_ENCRYPTED != 0 {
+            if header.has_data_descriptor() {
+                fail!(
+                    Unsupported,
+                    "Can't find the end of encrypted file {}",
+                    header.name
+                );
+            }
+            self.skip_entry(header)?;
+            return Ok(Err(ClassifiedError::new(
+                FailureKind::Unsupported,
+                "Encrypted files are not supported",
+            )
+            .into()));
+        }
+        match header.compression_method {
+            METHOD_STORED if header.has_data_descriptor() => fail!(
+                Archive,
+                "Can't find the end of uncompressed file {} without a central directory",
+                header.name
+            ),
+            METHOD_STORED | METHOD_DEFLATED | METHOD_BZIP2 | METHOD_ZSTD => {}
+            method if header.has_data_descriptor() => fail!(
+                Unsupported,
+                "Can't find the end of file {} compressed with unsupported method {method}",
+                header.name
+            ),
+            method => {
+                self.skip_entry(header)?;
+                return Ok(Err(ClassifiedError::new(
+                    FailureKind::Unsupported,
+                    format!("Unsupported compression method {method}"),
+                )
+                .into()));
+            }
+        }
+        let (result, crc32, uncompressed_size) = if header.has_data_descriptor() {
+            let mut data = Crc32Reader::new(decompressor(header, &mut self.reader)?);
+            let result = extract(&mut data);
+            // The only way to find the end of the data is to decompress all
+            // of it, even if extraction failed.
+            std::io::copy(&mut data, &mut std::io::sink())
+                .with_context(|| format!("Failed to decompress {}", header.name))?;
+            (result, data.hasher.finalize(), data.count)
+        } else {
+            let mut raw = (&mut self.reader).take(header.compressed_size);
+            let mut data = Crc32Reader::new(decompressor(header, &mut raw)?);
+            let result = extract(&mut data);
+            // Check the CRC of the whole file, but don't decompress the
+            // rest if extraction failed, since it may have been refused for
+            // being too large.
+            if result.is_ok() {
+                std::io::copy(&mut data, &mut std::io::sink())
+                    .with_context(|| format!("Failed to decompress {}", header.name))?;
+            }
+            let (crc32, uncompressed_size) = (data.hasher.clone().finalize(), data.count);
+            drop(data);
+            // Skip whatever wasn't decompressed, including anything after
+            // the end of the compressed data.
+            std::io::copy(&mut raw, &mut std::io::sink())?;
+            (result, crc32, uncompressed_size)
+        };
+        let data_end = self.position();
+        let expected = if header.has_data_descriptor() {
+            self.read_data_descriptor(header.zip64)?
+        } else {
+            EntryTrailer {
+                crc32: header.crc32,
+                compressed_size: header.compressed_size,
+                uncompressed_size: header.uncompressed_size,
+            }
+        };
+        if data_end - header.data_start != expected.compressed_size {
+            fail!(Archive, "Compressed size of {} is incorrect", header.name);
+        }
+        Ok(result.and_then(|_| {
+            if crc32 != expected.crc32 || uncompressed_size != expected.uncompressed_size {
+                fail!(Archive, "Data does not match its CRC or size");
+            }
+            Ok(expected)
+        }))
+    }
+
+    /// Read and discard the rest of the stream, such as the central
+    /// directory.
+    pub(crate) fn skip_to_end(&mut self) -> std::io::Result<u64> {
+        std::io::copy(&mut self.reader, &mut std::io::sink())
+    }
+
+    fn read_data_descriptor(&mut self, zip64: bool) -> Result<EntryTrailer> {
+        let mut read_u32 = || -> Result<u32> {
+            let mut buf = [0u8; 4];
+            self.reader
+                .read_exact(&mut buf)
+                .with_context(|| "Data descriptor is truncated")?;
+            Ok(le_u32(&buf))
+        };
+        // The signature is optional.
+        let mut crc32 = read_u32()?;
+        if crc32 == DATA_DESCRIPTOR_SIGNATURE {
+            crc32 = read_u32()?;
+        }
+        let mut read_size = || -> Result<u64> {
+            Ok(if zip64 {
+                read_u32()? as u64 | (read_u32()? as u64) << 32
+            } else {
+                read_u32()? as u64
+            })
+        };
+        Ok(EntryTrailer {
+            crc32,
+            compressed_size: read_size()?,
+            uncompressed_size: read_size()?,
+        })
+    }
+}
+
+/// Counts how many bytes have been read from a stream.
+struct CountingReader<R: Read> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        self.count += bytes_read as u64;
+        Ok(bytes_read)
+    }
+}
+
+/// Copies everything read from a stream into a writer.
+pub(crate) struct TeeReader<R: Read, W: Write> {
+    inner: R,
+    copy: W,
+}
+
+impl<R: Read, W: Write> TeeReader<R, W> {
+    pub(crate) fn new(inner: R, copy: W) -> Self {
+        Self { inner, copy }
+    }
+}
+
+impl<R: Read, W: Write> Read for TeeReader<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        self.copy.write_all(&buf[..bytes_read])?;
+        Ok(bytes_read)
+    }
+}
+
+/// Wraps the compressed data of a file in a reader which decompresses it.
+fn decompressor<'a>(
+    header: &LocalFileHeader,
+    raw: impl BufRead + 'a,
+) -> Result<Box<dyn Read + 'a>> {
+    Ok(match header.compression_method {
+        METHOD_DEFLATED => Box::new(flate2::bufread::DeflateDecoder::new(raw)),
+        METHOD_BZIP2 => Box::new(bzip2::bufread::BzDecoder::new(raw)),
+        METHOD_ZSTD => Box::new(zstd::stream::read::Decoder::with_buffer(raw)?.single_frame()),
+        _ => Box::new(raw),
+    })
+}
+
+/// Calculates the CRC and size of data as it's read.
+struct Crc32Reader<R: Read> {
+    inner: R,
+    hasher: crc32fast::Hasher,
+    count: u64,
+}
+
+impl<R: Read> Crc32Reader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+            count: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for Crc32Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..bytes_read]);
+        self.count += bytes_read as u64;
+        Ok(bytes_read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+    use test_log::test;
+    use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+    use super::ZipStreamReader;
+
+    fn read_all(zip_data: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let mut stream = ZipStreamReader::new(zip_data);
+        let mut files = Vec::new();
+        while let Some(header) = stream.next_header().unwrap() {
+            let mut contents = Vec::new();
+            stream
+                .read_entry(&header, |reader| {
+                    reader.read_to_end(&mut contents)?;
+                    Ok(())
+                })
+                .unwrap()
+                .unwrap();
+            files.push((header.name, contents));
+        }
+        files
+    }
+
+    #[test]
+    fn test_stream_reader() {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for method in [
+            CompressionMethod::Stored,
+            CompressionMethod::Deflated,
+            CompressionMethod::Bzip2,
+            CompressionMethod::Zstd,
+        ] {
+            let options = FileOptions::default().compression_method(method);
+            zip.start_file(format!("{method}.txt"), options).unwrap();
+            zip.write_all(&[b'a'; 1000]).unwrap();
+        }
+        zip.add_directory("dir/", Default::default()).unwrap();
+        let zip_data = zip.finish().unwrap().into_inner();
+        let files = read_all(&zip_data);
+        let names: Vec<_> = files.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "Stored.txt",
+                "Deflated.txt",
+                "Bzip2.txt",
+                "Zstd.txt",
+                "dir/"
+            ]
+        );
+        assert!(files[..4].iter().all(|(_, data)| data == &[b'a'; 1000]));
+    }
+
+    #[test]
+    fn test_data_descriptors() {
+        // Writing to a non-seekable stream makes zip-rs use data
+        // descriptors; it can't do that, so do it by hand.
+        let mut compressed = Vec::new();
+        let mut encoder =
+            flate2::write::DeflateEncoder::new(&mut compressed, flate2::Compression::default());
+        encoder.write_all(b"Contents of A\n").unwrap();
+        encoder.finish().unwrap();
+        let crc = crc32fast::hash(b"Contents of A\n");
+        let mut zip_data = Vec::new();
+        zip_data.extend(0x04034b50u32.to_le_bytes());
+        zip_data.extend([20, 0, 8, 0, 8, 0, 0, 0, 0x21, 0]);
+        zip_data.extend([0; 12]);
+        zip_data.extend([5, 0, 0, 0]);
+        zip_data.extend(b"a.txt");
+        zip_data.extend(&compressed);
+        zip_data.extend(0x08074b50u32.to_le_bytes());
+        zip_data.extend(crc.to_le_bytes());
+        zip_data.extend((compressed.len() as u32).to_le_bytes());
+        zip_data.extend(14u32.to_le_bytes());
+        zip_data.extend(0x06054b50u32.to_le_bytes());
+        assert_eq!(
+            read_all(&zip_data),
+            [("a.txt".to_string(), b"Contents of A\n".to_vec())]
+        );
+
+        // A bad CRC is reported as a problem with this file only.
+        let crc_offset = zip_data.len() - 16;
+        zip_data[crc_offset] ^= 1;
+        let mut stream = ZipStreamReader::new(&zip_data[..]);
+        let header = stream.next_header().unwrap().unwrap();
+        assert!(stream.read_entry(&header, |_| Ok(())).unwrap().is_err());
+        assert!(stream.next_header().unwrap().is_none());
+    }
+
+    /// A deflated file without a data descriptor.
+    fn deflated_entry(compressed: &[u8], crc: u32, uncompressed_size: u32) -> Vec<u8> {
+        let mut zip_data = Vec::new();
+        zip_data.extend(0x04034b50u32.to_le_bytes());
+        zip_data.extend([20, 0, 0, 0, 8, 0, 0, 0, 0x21, 0]);
+        zip_data.extend(crc.to_le_bytes());
+        zip_data.extend((compressed.len() as u32).to_le_bytes());
+        zip_data.extend(uncompressed_size.to_le_bytes());
+        zip_data.extend([5, 0, 0, 0]);
+        zip_data.extend(b"a.txt");
+        zip_data.extend(compressed);
+        zip_data
+    }
+
+    #[test]
+    fn test_skip_after_failure() {
+        // Data which can't be decompressed isn't, if extraction has already
+        // failed.
+        let mut zip_data = deflated_entry(&[0xff; 16], 0, 1000);
+        zip_data.extend(0x06054b50u32.to_le_bytes());
+        let mut stream = ZipStreamReader::new(&zip_data[..]);
+        let header = stream.next_header().unwrap().unwrap();
+        let result = stream.read_entry(&header, |_| Err(anyhow::anyhow!("Refused")));
+        assert!(matches!(result, Ok(Err(e)) if e.to_string() == "Refused"));
+        assert!(stream.next_header().unwrap().is_none());
+
+        // Anything after the end of the deflate stream is skipped.
+        let mut compressed = Vec::new();
+        let mut encoder =
+            flate2::write::DeflateEncoder::new(&mut compressed, flate2::Compression::default());
+        encoder.write_all(b"Contents of A\n").unwrap();
+        encoder.finish().unwrap();
+        compressed.extend([0; 3]);
+        let mut zip_data = deflated_entry(&compressed, crc32fast::hash(b"Contents of A\n"), 14);
+        zip_data.extend(0x06054b50u32.to_le_bytes());
+        assert_eq!(
+            read_all(&zip_data),
+            [("a.txt".to_string(), b"Contents of A\n".to_vec())]
+        );
+    }
+}
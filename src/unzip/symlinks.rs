@@ -0,0 +1,248 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    io::{ErrorKind, Read},
+    path::{Component, Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use filetime::FileTime;
+
+use super::{error::fail, FileMetadata, OverwritePolicy};
+
+/// The file type bits of a Unix mode.
+const S_IFMT: u32 = 0o170000;
+
+/// The file type of a symlink.
+const S_IFLNK: u32 = 0o120000;
+
+/// Symlink targets longer than this are rejected, rather than reading an
+/// arbitrary amount of data into memory.
+const MAX_SYMLINK_TARGET: u64 = 4096;
+
+/// What to do with a symlink whose target might be outside the output
+/// directory. Symlinks which are known to stay within the output
+/// directory are always created as symlinks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Fail to extract the symlink.
+    #[default]
+    Error,
+    /// Skip the symlink, logging a warning.
+    Skip,
+    /// Create a regular file containing the symlink's target instead.
+    /// This is how symlinks are extracted on platforms which don't
+    /// support them.
+    WriteAsFile,
+}
+
+/// Whether a file with this Unix mode is a symlink.
+pub(crate) fn is_symlink(unix_mode: Option<u32>) -> bool {
+    unix_mode.is_some_and(|mode| mode & S_IFMT == S_IFLNK)
+}
+
+/// Whether a symlink called `name` (relative to the output directory)
+/// pointing at `target` is guaranteed to stay within the output directory.
+///
+/// The check is purely lexical, so to be robust against chains of symlinks
+/// we only accept targets which consist of some `..` components followed by
+/// some normal components. The `..` components then only traverse real
+/// directories (because we refuse to write through symlinks) and the
+/// normal components only descend into directories or symlinks which
+/// themselves passed this check.
+pub(crate) fn target_is_contained(name: &Path, target: &Path) -> bool {
+    let mut depth = 0usize;
+    for component in name.parent().into_iter().flat_map(Path::components) {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::ParentDir => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    if target.as_os_str().is_empty() {
+        return false;
+    }
+    let mut seen_normal = false;
+    for component in target.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if !seen_normal && depth > 0 => depth -= 1,
+            Component::Normal(_) => seen_normal = true,
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+    true
+}
+
+/// Check that neither `path` within `root`, nor any of the directories
+/// leading to it, are symlinks, so that we don't write anything outside
+/// `root`.
+pub(crate) fn check_no_symlinks_in_path(root: &Path, path: &Path) -> Result<()> {
+    let mut components = path.components();
+    let mut path = root.to_path_buf();
+    for component in &mut components {
+        path.push(component);
+        match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                fail!(
+                    Security,
+                    "Refusing to write through symlink {}",
+                    path.display()
+                )
+            }
+            Ok(_) => {}
+            // Nothing deeper can exist, so can't be a symlink.
+            Err(e) if e.kind() == ErrorKind::NotFound => break,
+            Err(e) => return Err(e).with_context(|| format!("Failed to check {}", path.display())),
+        }
+    }
+    Ok(())
+}
+
+/// If `path` is an existing symlink, remove it so that we don't write
+/// through it.
+pub(crate) fn remove_existing_symlink(path: &Path) -> Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => {
+            std::fs::remove_file(path).with_context(|| "Failed to remove existing symlink")
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Read the target of a symlink, which is stored as the file contents.
+pub(crate) fn read_symlink_target(reader: impl Read) -> Result<Vec<u8>> {
+    let mut target = Vec::new();
+    reader
+        .take(MAX_SYMLINK_TARGET + 1)
+        .read_to_end(&mut target)
+        .with_context(|| "Failed to read symlink target")?;
+    if target.len() as u64 > MAX_SYMLINK_TARGET {
+        fail!(Security, "Symlink target is too long");
+    }
+    Ok(target)
+}
+
+#[cfg(unix)]
+pub(crate) fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    std::ffi::OsString::from_vec(bytes).into()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    String::from_utf8_lossy(&bytes).into_owned().into()
+}
+
+/// A symlink which we'll create once all regular files have been extracted.
+/// Creating symlinks last means that no file within the zip file can be
+/// written through a symlink which is also within the zip file.
+pub(crate) struct PendingSymlink {
+    /// The name of the symlink relative to the output directory.
+    pub(crate) name: PathBuf,
+    /// The index of the symlink in the central directory, if known.
+    pub(crate) index: Option<usize>,
+    /// What the symlink points at.
+    pub(crate) target: PathBuf,
+    /// The symlink's permissions and modification time, if known.
+    pub(crate) metadata: FileMetadata,
+}
+
+/// Create a symlink called `name` within `root`, pointing at `target`.
+pub(crate) fn create_symlink(
+    root: &Path,
+    name: &Path,
+    target: &Path,
+    mtime: Option<SystemTime>,
+    overwrite_policy: OverwritePolicy,
+) -> Result<()> {
+    if let Some(parent) = name.parent() {
+        // Other symlinks may have been created since we checked this.
+        check_no_symlinks_in_path(root, parent)?;
+    }
+    let out_path = root.join(name);
+    if !overwrite_policy.should_write(&out_path, || mtime)? {
+        log::info!("Skipping existing file {}", name.display());
+        return Ok(());
+    }
+    // Otherwise, creating the symlink fails if anything exists there.
+    if overwrite_policy.replaces_existing() {
+        match std::fs::symlink_metadata(&out_path) {
+            Ok(metadata) if metadata.is_dir() => {
+                return Err(std::io::Error::new(
+                    ErrorKind::AlreadyExists,
+                    "A directory already exists",
+                )
+                .into())
+            }
+            Ok(_) => {
+                std::fs::remove_file(&out_path).with_context(|| "Failed to remove existing file")?
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| "Failed to check for existing file"),
+        }
+    }
+    match symlink(target, &out_path) {
+        Err(e) if e.kind() == ErrorKind::AlreadyExists && overwrite_policy.skips_existing() => {
+            log::info!("Skipping existing file {}", name.display());
+            return Ok(());
+        }
+        result => result.with_context(|| "Failed to create symlink")?,
+    }
+    if let Some(mtime) = mtime {
+        filetime::set_symlink_file_times(
+            &out_path,
+            FileTime::now(),
+            FileTime::from_system_time(mtime),
+        )
+        .with_context(|| "Failed to set modification time")?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, path: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &Path, _path: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        ErrorKind::Unsupported,
+        "symlinks are not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use test_log::test;
+
+    use super::target_is_contained;
+
+    #[test]
+    fn test_target_is_contained() {
+        let contained =
+            |name: &str, target: &str| target_is_contained(Path::new(name), Path::new(target));
+        assert!(contained("link", "a.txt"));
+        assert!(contained("link", "./dir/a.txt"));
+        assert!(contained("dir/link", "../a.txt"));
+        assert!(contained("dir/sub/link", "../../dir/a.txt"));
+        assert!(!contained("link", "../a.txt"));
+        assert!(!contained("dir/link", "../../a.txt"));
+        assert!(!contained("link", "/etc/passwd"));
+        assert!(!contained("link", ""));
+        assert!(contained("link", "."));
+        assert!(contained("dir/link", ".."));
+        assert!(!contained("link", ".."));
+        // This could escape if `dir/other` were a symlink to `..`.
+        assert!(!contained("dir/link", "other/../a.txt"));
+    }
+}
@@ -0,0 +1,137 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::time::{Duration, SystemTime};
+
+use super::central_directory::{extra_fields, le_u32, le_u64};
+
+/// Extra field tag for the Info-ZIP extended timestamp, which stores
+/// times as Unix seconds.
+const EXTENDED_TIMESTAMP_TAG: u16 = 0x5455;
+
+/// Extra field tag for NTFS file times, which are stored in units of
+/// 100ns since 1601.
+const NTFS_TAG: u16 = 0x000a;
+
+/// Within the NTFS extra field, the attribute tag containing the file
+/// times, and its size.
+const NTFS_FILE_TIMES_TAG: u16 = 0x0001;
+const NTFS_FILE_TIMES_SIZE: usize = 24;
+
+/// Seconds between the NTFS epoch (1601) and the Unix epoch.
+const NTFS_EPOCH_OFFSET: u64 = 11_644_473_600;
+
+/// Within the extended timestamp, this flag indicates the modification
+/// time is present.
+const EXTENDED_TIMESTAMP_MTIME: u8 = 1;
+
+/// Work out the modification time of a file within a zip file. We prefer
+/// the NTFS extra field, then the extended timestamp extra field, since
+/// these have better precision and range and are unambiguously UTC.
+/// Otherwise we fall back to the MS-DOS timestamp which every zip file has.
+/// That doesn't record a time zone; as `zip-rs` does, we interpret it as
+/// UTC, though the zip file was most likely created in local time.
+pub(crate) fn modification_time(extra: &[u8], last_modified: zip::DateTime) -> Option<SystemTime> {
+    extra_fields(extra)
+        .find_map(|(tag, field)| match tag {
+            NTFS_TAG => parse_ntfs(field),
+            _ => None,
+        })
+        .or_else(|| {
+            extra_fields(extra).find_map(|(tag, field)| match tag {
+                EXTENDED_TIMESTAMP_TAG => parse_extended_timestamp(field),
+                _ => None,
+            })
+        })
+        .or_else(|| last_modified.to_time().ok().map(SystemTime::from))
+}
+
+fn parse_ntfs(field: &[u8]) -> Option<SystemTime> {
+    // Four reserved bytes, then a series of (tag, size, data) attributes,
+    // laid out the same way as extra fields themselves.
+    let (_, mtime) = extra_fields(field.get(4..)?)
+        .find(|(tag, data)| *tag == NTFS_FILE_TIMES_TAG && data.len() >= NTFS_FILE_TIMES_SIZE)?;
+    let ticks = le_u64(mtime);
+    if ticks == 0 {
+        return None;
+    }
+    let since_1601 = Duration::new(ticks / 10_000_000, (ticks % 10_000_000) as u32 * 100);
+    let epoch_offset = Duration::from_secs(NTFS_EPOCH_OFFSET);
+    if since_1601 >= epoch_offset {
+        SystemTime::UNIX_EPOCH.checked_add(since_1601 - epoch_offset)
+    } else {
+        SystemTime::UNIX_EPOCH.checked_sub(epoch_offset - since_1601)
+    }
+}
+
+fn parse_extended_timestamp(field: &[u8]) -> Option<SystemTime> {
+    let (&flags, field) = field.split_first()?;
+    if flags & EXTENDED_TIMESTAMP_MTIME == 0 || field.len() < 4 {
+        return None;
+    }
+    let seconds = le_u32(field) as i32;
+    let offset = Duration::from_secs(seconds.unsigned_abs() as u64);
+    if seconds >= 0 {
+        SystemTime::UNIX_EPOCH.checked_add(offset)
+    } else {
+        SystemTime::UNIX_EPOCH.checked_sub(offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+    use test_log::test;
+
+    use super::modification_time;
+
+    #[test]
+    fn test_dos_timestamp() {
+        let dos = zip::DateTime::from_date_and_time(2001, 9, 9, 1, 46, 40).unwrap();
+        assert_eq!(
+            modification_time(&[], dos),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000))
+        );
+    }
+
+    #[test]
+    fn test_extended_timestamp() {
+        let dos = zip::DateTime::from_date_and_time(1990, 1, 1, 0, 0, 0).unwrap();
+        // An unrelated field, then an extended timestamp with mtime and atime.
+        let mut extra = vec![0x34, 0x12, 2, 0, 0xff, 0xff];
+        extra.extend([0x55, 0x54, 9, 0, 3]);
+        extra.extend(1_000_000_001u32.to_le_bytes());
+        extra.extend(5u32.to_le_bytes());
+        assert_eq!(
+            modification_time(&extra, dos),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_001))
+        );
+        // Without the mtime flag we fall back to the DOS time.
+        extra[10] = 2;
+        assert_eq!(
+            modification_time(&extra, dos),
+            dos.to_time().ok().map(SystemTime::from)
+        );
+    }
+
+    #[test]
+    fn test_ntfs_timestamp() {
+        let dos = zip::DateTime::from_date_and_time(1990, 1, 1, 0, 0, 0).unwrap();
+        let ticks = (11_644_473_600u64 + 1_000_000_002) * 10_000_000 + 5;
+        let mut extra = vec![0x55, 0x54, 5, 0, 1];
+        extra.extend(1_000_000_001u32.to_le_bytes());
+        extra.extend([0x0a, 0, 32, 0, 0, 0, 0, 0, 1, 0, 24, 0]);
+        extra.extend(ticks.to_le_bytes());
+        extra.extend([0; 16]);
+        // NTFS times take precedence over the extended timestamp.
+        assert_eq!(
+            modification_time(&extra, dos),
+            Some(SystemTime::UNIX_EPOCH + Duration::new(1_000_000_002, 500))
+        );
+    }
+}
@@ -0,0 +1,229 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::Result;
+
+use super::{
+    central_directory::{CentralDirectory, LOCAL_FILE_HEADER_SIZE},
+    error::fail,
+    report::EntryFailure,
+};
+
+/// What to do about a suspicious zip file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ValidationPolicy {
+    /// Refuse to extract it.
+    #[default]
+    Error,
+    /// Log a warning, then extract it anyway.
+    Warn,
+    /// Extract it without comment.
+    Allow,
+}
+
+impl ValidationPolicy {
+    /// Apply this policy to a problem we've found.
+    pub(crate) fn apply(self, problem: impl FnOnce() -> String) -> Result<()> {
+        match self {
+            Self::Error => fail!(Security, "{}", problem()),
+            Self::Warn => {
+                log::warn!("{}", problem());
+                Ok(())
+            }
+            Self::Allow => Ok(()),
+        }
+    }
+}
+
+/// Check whether the data of any entries overlaps, which is how
+/// non-recursive zip bombs get so much data out of a small zip file.
+///
+/// We work out where each entry's data starts from the central directory
+/// rather than reading every local header, which would be slow for a URI.
+/// That assumes the local header has no extra fields, so gives the earliest
+/// the data can start: any overlap we report is real, but one involving
+/// the local header's extra fields might be missed.
+pub(crate) fn check_overlapping_entries(
+    central_directory: &CentralDirectory,
+    policy: ValidationPolicy,
+) -> Result<()> {
+    if policy == ValidationPolicy::Allow {
+        return Ok(());
+    }
+    let mut entries: Vec<_> = central_directory.entries.iter().collect();
+    entries.sort_by_key(|entry| entry.header_start);
+    // The entry which extends furthest of those we've seen so far.
+    let mut furthest: Option<(u64, &str)> = None;
+    for entry in entries {
+        if let Some((end, name)) = furthest {
+            if entry.header_start < end {
+                policy.apply(|| format!("Entries {} and {} overlap", name, entry.name))?;
+            }
+        }
+        let Some(data_start) = entry
+            .header_start
+            .checked_add(LOCAL_FILE_HEADER_SIZE + entry.name_len)
+        else {
+            fail!(
+                Archive,
+                "Entry {} starts beyond any possible zip file",
+                entry.name
+            );
+        };
+        let end = data_start.saturating_add(entry.compressed_size);
+        if furthest.map_or(true, |(furthest_end, _)| end > furthest_end) {
+            furthest = Some((end, &entry.name));
+        }
+    }
+    Ok(())
+}
+
+/// Check whether any of the entries we're going to extract would be
+/// written to the same path as an earlier one, in which case which one wins
+/// would depend on thread timing. Repeated directories are harmless, so are
+/// ignored. Names which differ only in case are only compared as
+/// duplicates if `case_insensitive` is set. If the policy is to refuse them, the later entries are removed
+/// from `selected` and returned as failures, which is also what happens
+/// when streaming, since then we only find out about them as they arrive.
+pub(crate) fn check_duplicate_names(
+    central_directory: &CentralDirectory,
+    selected: &mut Vec<usize>,
+    policy: ValidationPolicy,
+    case_insensitive: bool,
+) -> Vec<EntryFailure> {
+    let mut failures = Vec::new();
+    if policy == ValidationPolicy::Allow {
+        return failures;
+    }
+    let mut seen: HashMap<PathBuf, bool> = HashMap::new();
+    selected.retain(|&i| {
+        let entry = &central_directory.entries[i];
+        let Some(name) = entry.enclosed_name() else {
+            // We'll report this when we come to extract it.
+            return true;
+        };
+        match seen.insert(output_path_key(name, case_insensitive), entry.is_dir()) {
+            Some(was_dir) if !(was_dir && entry.is_dir()) => {
+                match policy.apply(|| duplicate_name_problem(name)) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        failures.push(EntryFailure::new(&entry.name, Some(i), e));
+                        false
+                    }
+                }
+            }
+            _ => true,
+        }
+    });
+    failures
+}
+
+/// Describe the problem of an entry whose path we've already extracted to.
+pub(crate) fn duplicate_name_problem(name: &Path) -> String {
+    format!(
+        "More than one entry would be extracted to {}",
+        name.display()
+    )
+}
+
+/// The path an entry would be extracted to, in a form in which two paths
+/// compare equal if they would be the same file. `..` is resolved, and if
+/// `case_insensitive` is set, case is ignored, as the default filesystems
+/// on Windows and macOS do.
+pub(crate) fn output_path_key(name: &Path, case_insensitive: bool) -> PathBuf {
+    let mut key = PathBuf::new();
+    for component in name.components() {
+        match component {
+            Component::ParentDir => {
+                key.pop();
+            }
+            Component::Normal(part) if case_insensitive => {
+                key.push(part.to_string_lossy().to_lowercase())
+            }
+            Component::Normal(part) => key.push(part),
+            _ => (),
+        }
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use test_log::test;
+
+    use super::{check_duplicate_names, check_overlapping_entries, ValidationPolicy};
+    use crate::unzip::central_directory::{CentralDirectory, CentralDirectoryEntry};
+
+    fn entry(name: &str, header_start: u64, compressed_size: u64) -> CentralDirectoryEntry {
+        CentralDirectoryEntry {
+            name: name.to_string(),
+            name_len: name.len() as u64,
+            header_start,
+            compressed_size,
+            ..Default::default()
+        }
+    }
+
+    fn central_directory(entries: Vec<CentralDirectoryEntry>) -> CentralDirectory {
+        CentralDirectory { entries, start: 0 }
+    }
+
+    #[test]
+    fn test_overlapping_entries() {
+        let policy = ValidationPolicy::Error;
+        let separate = central_directory(vec![entry("b", 40, 5), entry("a", 0, 9)]);
+        assert!(check_overlapping_entries(&separate, policy).is_ok());
+        let overlapping = central_directory(vec![entry("a", 0, 100), entry("b", 40, 5)]);
+        assert!(check_overlapping_entries(&overlapping, policy).is_err());
+        assert!(check_overlapping_entries(&overlapping, ValidationPolicy::Warn).is_ok());
+        // Both point at the same local header.
+        let shared = central_directory(vec![entry("a", 0, 9), entry("b", 0, 9)]);
+        assert!(check_overlapping_entries(&shared, policy).is_err());
+        // The sizes come from the zip file, so may be nonsense.
+        let absurd = central_directory(vec![entry("a", u64::MAX - 10, 9)]);
+        assert!(check_overlapping_entries(&absurd, ValidationPolicy::Warn).is_err());
+    }
+
+    #[test]
+    fn test_duplicate_names() {
+        let policy = ValidationPolicy::Error;
+        let cd = central_directory(vec![
+            entry("dir/", 0, 0),
+            entry("dir/a", 0, 0),
+            entry("dir/", 0, 0),
+            entry("dir/./a", 0, 0),
+        ]);
+        let check = |selected: &[usize], policy| {
+            let mut selected = selected.to_vec();
+            let failures = check_duplicate_names(&cd, &mut selected, policy, false);
+            let failed: Vec<_> = failures.iter().map(|failure| failure.index).collect();
+            (selected, failed)
+        };
+        assert_eq!(check(&[0, 1, 2], policy), (vec![0, 1, 2], vec![]));
+        assert_eq!(check(&[0, 1, 3], policy), (vec![0, 1], vec![Some(3)]));
+        assert_eq!(
+            check(&[0, 1, 3], ValidationPolicy::Allow),
+            (vec![0, 1, 3], vec![])
+        );
+        // These would be the same file on case-insensitive filesystems, but
+        // are only duplicates if we ask.
+        let cd = central_directory(vec![entry("README", 0, 0), entry("dir/../readme", 0, 0)]);
+        let mut selected = vec![0, 1];
+        assert!(check_duplicate_names(&cd, &mut selected, policy, false).is_empty());
+        assert_eq!(selected, [0, 1]);
+        let failures = check_duplicate_names(&cd, &mut selected, policy, true);
+        assert_eq!(selected, [0]);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].name, "dir/../readme");
+    }
+}
@@ -69,9 +69,12 @@ impl httptest::responders::Responder for RangeAwareResponse {
                                 .get(1)
                                 .and_then(|s| s.as_str().parse::<usize>().ok())
                                 .unwrap();
+                            // HTTP ranges are inclusive of the last byte,
+                            // and may extend beyond the end of the resource.
                             let to = captures
                                 .get(2)
                                 .and_then(|s| s.as_str().parse::<usize>().ok())
+                                .map(|to| std::cmp::min(to + 1, body.len()))
                                 .unwrap();
                             (body.slice(from..to), to - from)
                         } else {